@@ -1,20 +1,26 @@
 use {
-    anstream::{eprint, print},
+    anstream::{eprint, eprintln, print, println},
     anyhow::{anyhow, Result},
-    clap::{builder::Styles, ArgAction::Count, Parser},
+    clap::{
+        builder::Styles, error::ErrorKind, ArgAction::Count, CommandFactory, Parser, ValueEnum,
+    },
     dep_graph::{DepGraph, Node},
     expanduser::expanduser,
     glob::glob,
-    globset::{Glob, GlobMatcher},
-    indexmap::IndexMap,
+    globset::{Glob, GlobBuilder, GlobMatcher},
+    indexmap::{IndexMap, IndexSet},
     lazy_static::lazy_static,
     owo_colors::{OwoColorize, Style},
     pulldown_cmark as pd,
     regex::Regex,
     sprint::{style, ColorOverride, Command, Pipe, Shell},
     std::{
-        collections::HashSet,
+        collections::{HashMap, HashSet},
+        io::{IsTerminal, Write},
         path::{Path, PathBuf},
+        process::Stdio,
+        sync::{Mutex, OnceLock},
+        time::{Duration, Instant},
     },
 };
 
@@ -38,6 +44,7 @@ macro_rules! ecprint {
 macro_rules! error {
     ($code:expr, $($x:tt)*) => {
         ecprint!(*ERROR, $($x)*);
+        run_failure_hook($code);
         std::process::exit($code);
     };
 }
@@ -45,26 +52,178 @@ macro_rules! error {
 //--------------------------------------------------------------------------------------------------
 
 lazy_static! {
-    static ref BULLET: Style = style("#888888").expect("style");
-    static ref CONFIGURATION: Style = style("#FFFF22+bold").expect("style");
-    static ref ERROR: Style = style("red+bold").expect("style");
-    static ref FENCE: Style = style("#555555").expect("style");
-    static ref FILE_TARGET: Style = style("#44FFFF+bold").expect("style");
-    static ref TARGET: Style = style("#FF22FF+bold").expect("style");
-    static ref UP_TO_DATE: Style = style("#00FF00+italic").expect("style");
+    static ref BULLET: Style = themed("#888888", "#777777");
+    static ref COMMAND: Style = themed("#00FFFF+bold", "#006688+bold");
+    static ref COMMAND_ERROR: Style = themed("#FF0000+bold+italic", "#CC0000+bold+italic");
+    static ref CONFIGURATION: Style = themed("#FFFF22+bold", "#886600+bold");
+    static ref DEBUG: Style = themed("#AAAAAA", "#666666");
+    static ref ERROR: Style = themed("red+bold", "red+bold");
+    static ref FENCE: Style = themed("#555555", "#888888");
+    static ref FILE_TARGET: Style = themed("#44FFFF+bold", "#007777+bold");
+    static ref KEYWORD: Style = themed("#FF8800+bold", "#AA4400+bold");
+    static ref STRING: Style = themed("#88FF88", "#227722");
+    static ref TARGET: Style = themed("#FF22FF+bold", "#AA00AA+bold");
+    static ref UP_TO_DATE: Style = themed("#00FF00+italic", "#008800+italic");
+    static ref VARIABLE: Style = themed("#FFFF88", "#777700");
+    static ref WARNING: Style = themed("yellow+bold", "#AA6600+bold");
+}
+
+/// Color theme (`--theme`) for the styles
+static THEME: OnceLock<Theme> = OnceLock::new();
+
+/// Parse the style from the dark or light palette for the theme
+fn themed(dark: &str, light: &str) -> Style {
+    let s = if THEME.get() == Some(&Theme::Light) {
+        light
+    } else {
+        dark
+    };
+    style(s).expect("style")
 }
 
-fn print_file_target(name: &str) {
-    cprint!(*TARGET, "# `{name}`\n\n");
+fn print_file_target(name: &str, progress: &str) {
+    cprint!(*TARGET, "# {progress}`{name}`\n\n");
 }
 
-fn print_target(name: &str) {
-    cprint!(*TARGET, "# {name}\n\n");
+fn print_target(name: &str, progress: &str) {
+    cprint!(*TARGET, "# {progress}{name}\n\n");
 }
 
-fn print_list_file_target(name: &str, level: usize) {
-    print_bullet(level);
-    cprint!(*FILE_TARGET, "`{name}`\n");
+/// Print markdown with headings, list bullets, and code block fences styled, and recipes
+/// highlighted for their code block's language (plain unless the output is a terminal or `--color
+/// always`)
+fn print_markdown(s: &str) {
+    let mut language = None;
+    for line in s.lines() {
+        if let Some(info) = line.strip_prefix("```") {
+            language = match language {
+                Some(_) => None,
+                None => Some(
+                    info.split_whitespace()
+                        .next()
+                        .unwrap_or_default()
+                        .to_string(),
+                ),
+            };
+            cprint!(*FENCE, "{line}\n");
+        } else if let Some(language) = &language {
+            for (token, text) in highlight(line, language) {
+                let style = match token {
+                    Token::Comment => *DEBUG,
+                    Token::Keyword => *KEYWORD,
+                    Token::Plain => Style::new(),
+                    Token::String => *STRING,
+                    Token::Variable => *VARIABLE,
+                };
+                cprint!(style, "{text}");
+            }
+            println!();
+        } else if line.starts_with('#') {
+            cprint!(*TARGET, "{line}\n");
+        } else if let Some(item) = line.strip_prefix("* ") {
+            cprint!(*BULLET, "* ");
+            println!("{item}");
+        } else {
+            println!("{line}");
+        }
+    }
+}
+
+/// Kind of a highlighted piece of a recipe line
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token {
+    Comment,
+    Keyword,
+    Plain,
+    String,
+    Variable,
+}
+
+/// Split a recipe line into highlighted pieces for a code block language: comments and strings,
+/// plus keywords and `$VAR`, `${VAR}`, and `{0}` variables in shell recipes (no language or an
+/// annotation, `sh`, `bash`, etc), and `#` or `//` comments in other languages
+fn highlight<'a>(line: &'a str, language: &str) -> Vec<(Token, &'a str)> {
+    let shell = language.is_empty() || language.starts_with('{') || is_shell(language);
+    let (comment, keywords): (&str, &[&str]) = match language {
+        _ if shell => (
+            "#",
+            &[
+                "case", "do", "done", "elif", "else", "esac", "fi", "for", "function", "if", "in",
+                "then", "until", "while",
+            ],
+        ),
+        "python" | "python3" => (
+            "#",
+            &[
+                "and", "def", "elif", "else", "for", "if", "import", "in", "not", "or", "return",
+                "while",
+            ],
+        ),
+        "perl" | "ruby" | "awk" | "make" => ("#", &[]),
+        _ => ("//", &[]),
+    };
+    let word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut tokens = vec![];
+    let mut plain = 0;
+    let mut i = 0;
+    while i < line.len() {
+        let rest = &line[i..];
+        let c = rest.chars().next().unwrap();
+        let boundary = line[..i].chars().next_back().is_none_or(|x| !word(x));
+        let end = if rest.starts_with(comment)
+            && (comment != "#"
+                || line[..i]
+                    .chars()
+                    .next_back()
+                    .is_none_or(char::is_whitespace))
+        {
+            Some((Token::Comment, line.len()))
+        } else if c == '\'' || c == '"' {
+            // Up to the closing quote, skipping escaped double quotes
+            let mut escaped = false;
+            let close = rest[1..].find(|x| {
+                let found = x == c && !escaped;
+                escaped = c == '"' && x == '\\' && !escaped;
+                found
+            });
+            Some((Token::String, close.map_or(line.len(), |x| i + x + 2)))
+        } else if shell && c == '$' {
+            let name = &rest[1..];
+            let len = if name.starts_with('{') {
+                name.find('}').map_or(name.len(), |x| x + 1)
+            } else if name.starts_with(|x: char| "#?!$@*-".contains(x) || x.is_ascii_digit()) {
+                1
+            } else {
+                name.find(|x| !word(x)).unwrap_or(name.len())
+            };
+            (len > 0).then_some((Token::Variable, i + 1 + len))
+        } else if shell && c == '{' {
+            rest.find('}')
+                .filter(|x| *x > 1 && rest[1..*x].chars().all(|x| word(x) || x == '.'))
+                .map(|x| (Token::Variable, i + x + 1))
+        } else if boundary && word(c) {
+            let len = rest.find(|x| !word(x)).unwrap_or(rest.len());
+            keywords
+                .contains(&&rest[..len])
+                .then_some((Token::Keyword, i + len))
+        } else {
+            None
+        };
+        if let Some((token, end)) = end {
+            if plain < i {
+                tokens.push((Token::Plain, &line[plain..i]));
+            }
+            tokens.push((token, &line[i..end]));
+            plain = end;
+            i = end;
+        } else {
+            i += c.len_utf8();
+        }
+    }
+    if plain < line.len() {
+        tokens.push((Token::Plain, &line[plain..]));
+    }
+    tokens
 }
 
 fn print_bullet(level: usize) {
@@ -78,15 +237,35 @@ fn print_indent(level: usize) {
     }
 }
 
-fn print_list_target(name: &str, level: usize) {
+/// Print a target in a list, with the configuration file it is defined in (`-l --source`)
+fn print_list_target(target: &Target, level: usize, source: bool) {
     print_bullet(level);
-    println!("{name}");
+    if target.dtg.is_some() {
+        cprint!(*FILE_TARGET, "`{}`", target.name);
+    } else {
+        cprint!(*TARGET, "{}", target.name);
+    }
+    if source && !target.source.as_os_str().is_empty() {
+        cprint!(*DEBUG, " ({})", target.source.display());
+    }
+    println!();
 }
 
 fn print_up_to_date() {
     cprint!(*UP_TO_DATE, "*Up to date*\n");
 }
 
+fn print_skipped() {
+    cprint!(*UP_TO_DATE, "*Skipped*\n");
+}
+
+/// Print each line of a command with a prefix (`--dry-run-prefix`)
+fn print_prefixed(prefix: &str, command: &str) {
+    for line in command.lines() {
+        println!("{prefix}{line}");
+    }
+}
+
 fn print_fence() {
     cprint!(*FENCE, "```");
 }
@@ -96,15 +275,21 @@ fn print_end_fence() {
     println!("\n");
 }
 
-fn print_list_file_targets(target: &str, targets: &IndexMap<String, Target>, level: usize) {
+fn print_list_file_targets(
+    target: &str,
+    targets: &IndexMap<String, Target>,
+    level: usize,
+    sort: bool,
+    source: bool,
+) {
     let target = targets.get(target).unwrap();
-    if target.dtg.is_some() {
-        print_list_file_target(&target.name, level);
-    } else {
-        print_list_target(&target.name, level);
+    print_list_target(target, level, source);
+    let mut dependencies = target.dependencies.iter().collect::<Vec<_>>();
+    if sort {
+        dependencies.sort();
     }
-    for dep in &target.dependencies {
-        print_list_file_targets(dep, targets, level + 1);
+    for dep in dependencies {
+        print_list_file_targets(dep, targets, level + 1, sort, source);
     }
 }
 
@@ -119,25 +304,144 @@ const STYLES: Styles = Styles::styled()
     .valid(clap_cargo::style::VALID)
     .invalid(clap_cargo::style::INVALID);
 
-#[derive(Debug, Parser)]
-#[command(about, version, max_term_width = 80, styles = STYLES)]
+#[derive(Clone, Debug, Parser)]
+#[command(
+    about,
+    version,
+    disable_version_flag = true,
+    max_term_width = 80,
+    styles = STYLES
+)]
 struct Cli {
     /// List targets/dependencies
     #[arg(short = 'l')]
     list_targets: bool,
 
+    /// Sort the listed targets/dependencies alphabetically
+    #[arg(long, requires = "list_targets")]
+    sort: bool,
+
+    /// Show the configuration file each listed target is defined in
+    #[arg(long, requires = "list_targets")]
+    source: bool,
+
+    /// Print a target's direct dependencies
+    #[arg(long, value_name = "NAME")]
+    print_deps: Option<String>,
+
+    /// Print the order in which a target and its dependencies would be processed
+    #[arg(long, value_name = "NAME")]
+    show_order: Option<String>,
+
+    /// Print a report on a target without running anything
+    #[arg(long, value_name = "NAME")]
+    describe: Option<String>,
+
+    /// Print a hash of a target's recipes, dependencies, and source files for use as a cache key
+    #[arg(long, value_name = "NAME")]
+    fingerprint: Option<String>,
+
+    /// Print the dependency graph
+    #[arg(long, value_name = "FORMAT")]
+    graph: Option<GraphFormat>,
+
+    /// Check the configuration for problems without running any recipes
+    #[arg(long)]
+    check: bool,
+
+    /// Treat warnings as errors
+    #[arg(long)]
+    strict: bool,
+
+    /// Print tab-separated target names and descriptions for shell completion
+    #[arg(long, hide = true)]
+    complete_targets: bool,
+
+    /// Print the configuration as normalized markdown
+    #[arg(long)]
+    format_config: bool,
+
+    /// Print the effective configuration (includes merged, `--profile` recipes selected, `{args}`
+    /// substituted) as markdown
+    #[arg(long)]
+    effective_config: bool,
+
     /// Force processing
     #[arg(short = 'B')]
     force_processing: bool,
 
+    /// Run the target(s) regardless of and without their dependencies
+    #[arg(long)]
+    no_deps: bool,
+
+    /// Treat a file as changed, so targets depending on it are processed
+    #[arg(long, value_name = "PATH")]
+    changed: Vec<String>,
+
+    /// Skip a target and its dependencies unless needed by another target
+    #[arg(long, value_name = "NAME")]
+    exclude: Vec<String>,
+
+    /// Use symlinks' own modification times instead of their targets'
+    #[arg(long)]
+    no_follow_symlinks: bool,
+
+    /// Run targets' dependency scanner commands (`{deps-command=...}`)
+    #[arg(long)]
+    allow_dynamic_deps: bool,
+
     /// Dry run
     #[arg(short = 'n')]
     dry_run: bool,
 
+    /// Run recipes without a custom command as scripts in a login shell (`bash -leo pipefail`)
+    #[arg(long)]
+    login_shell: bool,
+
+    /// Print each command line with a prefix in a dry run
+    #[arg(long, value_name = "PREFIX", requires = "dry_run")]
+    dry_run_prefix: Option<String>,
+
+    /// Warn if a file target is older than a dependency after running its recipes
+    #[arg(long)]
+    verify_mtime: bool,
+
+    /// Run the target(s) N times regardless of whether outdated and print the pass/fail counts
+    #[arg(long, value_name = "N")]
+    repeat: Option<usize>,
+
+    /// Also run the dependencies N times (`--repeat N`)
+    #[arg(long, requires = "repeat")]
+    repeat_deps: bool,
+
+    /// Print the size and modification time of each file target that was (re)built
+    #[arg(long)]
+    list_produced: bool,
+
+    /// Don't print target headings (command output is still printed)
+    #[arg(long)]
+    no_headings: bool,
+
+    /// Explain which wildcard targets were considered for a file target and why one was applied
+    #[arg(long)]
+    explain: bool,
+
+    /// Print the targets that would run, in order, without running them
+    #[arg(long)]
+    plan_only: bool,
+
     /// Script mode
     #[arg(short)]
     script_mode: bool,
 
+    /// Run each target's recipes as one script, so shell state carries between them
+    #[arg(long, requires = "script_mode")]
+    one_shell: bool,
+
+    /// Pass recipes to the shell verbatim, without substituting variables
+    #[arg(long)]
+    no_substitution: bool,
+
     /// Verbose
     #[arg(short, action = Count)]
     verbose: u8,
@@ -146,29 +450,208 @@ struct Cli {
     #[arg(short, conflicts_with = "verbose")]
     quiet: bool,
 
+    /// Quiet unless a target fails, then show its output
+    #[arg(long, conflicts_with_all = ["quiet", "verbose"])]
+    quiet_unless_fail: bool,
+
+    /// Print each up to date target at verbose level 2 instead of a count
+    #[arg(long)]
+    verbose_uptodate: bool,
+
+    /// Run recipes with reduced priority (`nice -n N`)
+    #[arg(long, value_name = "N")]
+    nice: Option<i32>,
+
+    /// Pause before each target with recipes to run, skip, or abort it
+    #[arg(long)]
+    step: bool,
+
+    /// Skip confirmation prompts for `{confirm}` targets
+    #[arg(long)]
+    yes: bool,
+
+    /// Keep going after a target fails
+    #[arg(short)]
+    keep_going: bool,
+
+    /// Keep going after a target fails until the Nth failure
+    #[arg(long, value_name = "N", conflicts_with = "keep_going")]
+    keep_going_until: Option<usize>,
+
+    /// Abort if the run takes longer than the number of seconds
+    #[arg(long, value_name = "SECONDS")]
+    time_limit: Option<u64>,
+
+    /// Fail if a requested phony target has no dependencies or recipes
+    #[arg(long)]
+    fail_on_missing_recipe: bool,
+
+    /// Maximum dependency depth
+    #[arg(long, default_value_t = 1000, value_name = "N")]
+    max_depth: usize,
+
+    /// Lint shell recipes before running them
+    #[arg(long)]
+    lint_recipes: bool,
+
+    /// Linter command used by `--lint-recipes`
+    #[arg(long, default_value = "shellcheck -s bash -", value_name = "COMMAND")]
+    linter: String,
+
+    /// Only allow recipes to run the given commands (not a sandbox)
+    #[arg(long, value_name = "NAME", value_delimiter = ',')]
+    allow_commands: Option<Vec<String>>,
+
     /// Change directory
     #[arg(short = 'C', value_name = "PATH")]
     change_directory: Option<PathBuf>,
 
+    /// Create the `-C` directory if it does not exist
+    #[arg(long, requires = "change_directory")]
+    create_dir: bool,
+
     /// Configuration file(s)
     #[arg(short = 'f', default_value = "Makefile.md", value_name = "PATH")]
     config_files: Vec<PathBuf>,
 
+    /// Allow loading configuration files from a URL or `git:REPO#PATH`
+    #[arg(long)]
+    allow_remote: bool,
+
+    /// Resolve file paths and `{dirname}` relative to the current or configuration file directory
+    #[arg(long, value_enum, default_value = "cwd", value_name = "DIR")]
+    config_dir: ConfigDir,
+
+    /// Markdown heading level of targets
+    #[arg(
+        long,
+        default_value_t = 1,
+        value_name = "N",
+        value_parser = clap::value_parser!(u8).range(1..=6),
+    )]
+    heading_level: u8,
+
     /// Generate Makefile.md content [styles: rust]
     #[arg(short = 'g', value_name = "STYLE")]
     generate: Option<String>,
 
+    /// Write a report after processing [formats: json]
+    #[arg(long, value_name = "FORMAT=PATH", value_parser = parse_report)]
+    report: Option<PathBuf>,
+
+    /// Write the processed targets' timings in the Chrome trace event format
+    #[arg(long, value_name = "PATH")]
+    trace_file: Option<PathBuf>,
+
+    /// Write each target's combined output to `DIR/<target slug>[-<hash>].log`
+    #[arg(long, value_name = "DIR")]
+    log_dir: Option<PathBuf>,
+
     /// Force enable/disable terminal colors
     #[arg(long, value_enum, global = true, default_value = "auto")]
     color: ColorOverride,
 
+    /// Color theme for the terminal background
+    #[arg(long, value_enum, global = true, default_value = "auto")]
+    theme: Theme,
+
     /// Print readme
     #[arg(short)]
     readme: bool,
 
-    /// Target(s)
+    /// Print version
+    #[arg(short = 'V', long)]
+    version: bool,
+
+    /// Print the version, features (options and annotations), shells, and platform as JSON
+    #[arg(long, requires = "version")]
+    json: bool,
+
+    /// Pick a phony target interactively
+    #[arg(long)]
+    pick: bool,
+
+    /// Process all phony targets
+    #[arg(long, conflicts_with = "pick")]
+    all: bool,
+
+    /// Process all targets with a tag (`{tags=NAME,...}`)
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["pick", "all"])]
+    tag: Vec<String>,
+
+    /// Select recipes scoped to a profile (`{profile=NAME}`)
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Select alternative recipes (`{alt=NAME}`); defaults to each target's first alternative
+    #[arg(long, value_name = "NAME")]
+    alt: Option<String>,
+
+    /// Target(s) and named parameters (`NAME=VALUE`)
     #[arg(value_name = "NAME")]
     targets: Vec<String>,
+
+    /// Arguments for recipes' `{args}` variable
+    #[arg(last = true, value_name = "ARG")]
+    args: Vec<String>,
+}
+
+/// Directory that file paths and `{dirname}` are relative to (`--config-dir`)
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum ConfigDir {
+    /// Current directory
+    Cwd,
+
+    /// Configuration file's directory
+    Config,
+}
+
+/// Dependency graph format (`--graph`)
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum GraphFormat {
+    /// Graphviz DOT
+    Dot,
+
+    /// Mermaid flowchart
+    Mermaid,
+}
+
+/// Color theme (`--theme`)
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Theme {
+    /// Detect the terminal background via `COLORFGBG`, otherwise dark
+    Auto,
+
+    /// Palette for dark backgrounds
+    Dark,
+
+    /// Palette for light backgrounds
+    Light,
+}
+
+impl Theme {
+    /// Resolve `auto` from the background color in `COLORFGBG` (`FG;BG`), which some terminals
+    /// set; background colors 7 and 9-15 are light
+    fn resolve(self) -> Theme {
+        if self != Theme::Auto {
+            return self;
+        }
+        let background = std::env::var("COLORFGBG")
+            .ok()
+            .and_then(|x| x.rsplit(';').next().and_then(|x| x.parse::<u8>().ok()));
+        match background {
+            Some(7 | 9..=15) => Theme::Light,
+            _ => Theme::Dark,
+        }
+    }
+}
+
+/// Parse a `--report` value
+fn parse_report(s: &str) -> Result<PathBuf, String> {
+    match s.split_once('=') {
+        Some(("json", path)) if !path.is_empty() => Ok(PathBuf::from(path)),
+        _ => Err(String::from("expected `json=PATH`")),
+    }
 }
 
 //--------------------------------------------------------------------------------------------------
@@ -180,17 +663,44 @@ fn main() -> Result<()> {
 
     let cli = Cli::parse();
 
+    // Named parameters (`NAME=VALUE`) may be given with `--pick`, `--all`, or `--tag`, but targets
+    // may not
+    if (cli.pick || cli.all || !cli.tag.is_empty())
+        && cli.targets.iter().any(|x| parse_param(x).is_none())
+    {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "targets cannot be used with `--pick`, `--all`, or `--tag`",
+            )
+            .exit();
+    }
+
     cli.color.init();
+    COLOR.set(cli.color.clone()).expect("color");
+    THEME.set(cli.theme.resolve()).expect("theme");
 
     // Print the readme (`-r`)
     if cli.readme {
         #[cfg(unix)]
-        Pager::with_pager("bat -pl md").setup();
+        if let Some(pager) = readme_pager() {
+            Pager::with_pager(&pager).setup();
+        }
 
         print!("{}", include_str!("../README.md"));
         std::process::exit(0);
     }
 
+    // Print the version (`-V`, `--version --json`)
+    if cli.version {
+        if cli.json {
+            println!("{}", version_json());
+        } else {
+            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        }
+        std::process::exit(0);
+    }
+
     // Generate default Makefile.md content (`-g STYLE`)
     if let Some(style) = cli.generate {
         match style.as_str() {
@@ -206,6 +716,9 @@ fn main() -> Result<()> {
 
     // Change directory (`-C`)
     if let Some(dir) = &cli.change_directory {
+        if cli.create_dir {
+            std::fs::create_dir_all(dir)?;
+        }
         std::env::set_current_dir(dir)?;
     }
 
@@ -213,12 +726,30 @@ fn main() -> Result<()> {
     if cli.verbose >= 3 {
         cprint!(*CONFIGURATION, "# Configuration\n\n");
         print_fence();
-        println!("\n{cli:#?}");
+        cprint!(*DEBUG, "\n{cli:#?}\n");
         print_end_fence();
     }
 
+    // Abort the run if it exceeds the time limit (`--time-limit`)
+    if let Some(limit) = cli.time_limit {
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_secs(limit));
+
+            // Keep the main thread from printing the failure of a terminated command and exiting
+            // first
+            let _stdout = std::io::stdout().lock();
+
+            // Terminate the running command(s) and their children
+            if program_exists("pgrep") && program_exists("pkill") {
+                terminate_descendants(&std::process::id().to_string());
+            }
+
+            error!(16, "ERROR: Time limit of {limit}s exceeded!");
+        });
+    }
+
     // Process targets
-    Config::from(&cli.config_files)?.process(&cli)?;
+    Config::from(&cli)?.process(&cli)?;
 
     Ok(())
 }
@@ -228,12 +759,26 @@ fn main() -> Result<()> {
 fn add_node_and_deps(
     target: &str,
     cfg: &Config,
+    cli: &Cli,
     nodes: &mut Vec<Node<String>>,
-    processed: &mut HashSet<String>,
-    force_processing: bool,
     prev_dep: Option<String>,
+    depth: usize,
+    state: &mut State,
 ) {
+    // Guard against cycles and pathologically deep dependency chains (`--max-depth`)
+    if depth > cli.max_depth {
+        error!(
+            11,
+            "ERROR: Maximum dependency depth ({}) exceeded at target `{target}`!", cli.max_depth,
+        );
+    }
+
+    // Add a target shared by several dependents (a diamond-shaped graph) and its subtree only once
     let target = target.to_string();
+    if state.processed.contains(&target) {
+        return;
+    }
+
     let mut node = Node::new(target.clone());
     if let Some(prev_dep) = prev_dep {
         node.add_dep(prev_dep);
@@ -242,218 +787,1056 @@ fn add_node_and_deps(
         // If a file target, only add its dependencies if it is needed
         let add_deps = if let Some(ts) = t.dtg.as_ref() {
             let file_does_not_exist = !Path::new(&t.name).exists();
-            force_processing || file_does_not_exist || t.outdated(ts, &cfg.targets)
+            cli.force_processing || file_does_not_exist || t.outdated(ts, &cfg.targets)
         } else {
             true
         };
-        if add_deps {
+        // Only add the requested target (`--no-deps`)
+        if add_deps && !cli.no_deps {
             let mut prev_dep = None;
             for dependency in &t.dependencies {
+                // Prune an excluded dependency's subtree (`--exclude`); dependencies shared with
+                // other targets are still added via those targets
+                if cli.exclude.contains(dependency) {
+                    continue;
+                }
                 node.add_dep(dependency.to_owned());
-                add_node_and_deps(
-                    dependency,
-                    cfg,
-                    nodes,
-                    processed,
-                    force_processing,
-                    prev_dep,
-                );
+                add_node_and_deps(dependency, cfg, cli, nodes, prev_dep, depth + 1, state);
                 prev_dep = Some(dependency.to_owned());
             }
         }
 
         // Deduplicate nodes
-        if !processed.contains(&target) {
+        if state.processed.insert(target) {
             nodes.push(node);
-            processed.insert(target);
         }
     } else {
-        error!(5, "ERROR: Invalid target: `{target}`!");
+        state.problem(cli, 5, format!("Invalid target: `{target}`"));
+    }
+}
+
+/// Return true if processing a target will run its recipes or a matching wildcard target's
+/// recipes, as `process_target` decides
+fn will_run(name: &str, targets: &IndexMap<String, Target>, cli: &Cli) -> bool {
+    let Some(target) = targets.get(name) else {
+        return false;
+    };
+    let follow_symlinks = !cli.no_follow_symlinks;
+    let forced = cli.force_processing || cli.no_deps;
+    if let Some(ts) = target.dtg.as_ref() {
+        let file_does_not_exist = !Path::new(name).exists();
+        if target.recipes.is_empty() {
+            targets.values().any(|t| {
+                t.glob.as_ref().is_some_and(|glob| glob.is_match(name)) && {
+                    let re = Regex::new(&format!("{}$", &t.name[2..])).expect("regex");
+                    let dependency = re.replace(name, &t.dependencies[0][2..]).to_string();
+                    forced
+                        || file_does_not_exist
+                        || outdated(&dependency, name, follow_symlinks)
+                        || cli.changed.contains(&dependency)
+                }
+            })
+        } else {
+            forced || file_does_not_exist || target.outdated(ts, targets)
+        }
+    } else if let Some(output) = target.annotations.get("output") {
+        let ts = mtime(output, follow_symlinks);
+        forced
+            || !Path::new(output).exists()
+            || target.inputs().any(|x| mtime(x, follow_symlinks) > ts)
+            || target
+                .dependencies
+                .iter()
+                .any(|x| targets.get(x).is_some_and(|x| x.outdated(&ts, targets)))
+    } else {
+        !target.recipes.is_empty()
     }
 }
 
 fn process_target(
     target: &str,
     targets: &IndexMap<String, Target>,
-    dry_run: bool,
-    force_processing: bool,
-    verbose: u8,
-    quiet: bool,
-    script_mode: bool,
-) {
+    cli: &Cli,
+    state: &mut State,
+) -> Option<Status> {
     let target = target.to_owned();
-    let target = targets.get(&target).unwrap();
+    // An invalid target was already reported as a problem in a dry run
+    let target = targets.get(&target)?;
     if let Some(ts) = target.dtg.as_ref() {
         // File target...
         let file_does_not_exist = !Path::new(&target.name).exists();
         if target.recipes.is_empty() {
             // Try wildcard target
+            let mut matched = false;
             for t in targets.values() {
                 if let Some(glob) = t.glob.as_ref() {
                     if glob.is_match(&target.name) {
+                        matched = true;
                         let re = Regex::new(&format!("{}$", &t.name[2..])).expect("regex");
                         let extension = &t.dependencies[0][2..];
                         let dependency = re.replace(&target.name, extension).to_string();
                         let target_does_not_exist = !Path::new(&target.name).exists();
-                        if force_processing
+                        let apply = cli.force_processing
+                            || cli.no_deps
                             || target_does_not_exist
-                            || outdated(&dependency, &target.name)
-                        {
-                            Target::new(
-                                &target.name,
-                                true,
-                                None,
-                                &[dependency.clone()],
-                                t.recipes
-                                    .iter()
-                                    .map(|x| x.fix(&target.name, &dependency))
-                                    .collect(),
-                            )
-                            .run(dry_run, verbose, quiet, script_mode);
-                            return;
+                            || outdated(&dependency, &target.name, !cli.no_follow_symlinks)
+                            || cli.changed.contains(&dependency);
+                        if cli.explain {
+                            explain_wildcard(&target.name, &t.name, Some(&dependency), apply);
+                        }
+                        if apply {
+                            let result =
+                                t.instantiate(&target.name, &dependency).process(cli, state);
+                            return Some(result);
                         }
+                    } else if cli.explain {
+                        explain_wildcard(&target.name, &t.name, None, false);
                     }
                 }
             }
+            if cli.explain && !matched {
+                ecprint!(*DEBUG, "No wildcard target matches `{}`\n", target.name);
+            }
+
+            // A dry run also flags a file target with dependencies that would need to be built but
+            // has no recipe, which otherwise goes unnoticed if the file exists
+            let unbuildable = !target.dependencies.is_empty()
+                && (file_does_not_exist || (cli.dry_run && target.outdated(ts, targets)));
+            if unbuildable {
+                state.problem(
+                    cli,
+                    3,
+                    format!(
+                        "File target `{}` would be required to build but has no recipe",
+                        target.name
+                    ),
+                );
+                return None;
+            }
 
             if file_does_not_exist {
                 // File dependency (without commands) must exist
-                error!(3, "ERROR: File `{}` does not exist!", target.name);
+                state.problem(cli, 3, format!("File `{}` does not exist", target.name));
+                return None;
             }
             // Otherwise, file dependency exists so don't print or do anything
-        } else if force_processing || file_does_not_exist || target.outdated(ts, targets) {
-            // Process the target if `-B`, target has commands & file doesn't exist, or target is
-            // outdated
-            target.run(dry_run, verbose, quiet, script_mode);
-        } else if verbose >= 2 {
+            None
+        } else if cli.force_processing
+            || cli.no_deps
+            || file_does_not_exist
+            || target.outdated(ts, targets)
+        {
+            // Process the target if `-B` or `--no-deps`, target has commands & file doesn't exist,
+            // or target is outdated
+            Some(target.process(cli, state))
+        } else {
             // Otherwise, don't process the target
-            target.print_heading();
-            print_up_to_date();
+            if cli.verbose >= 2 && cli.verbose_uptodate {
+                target.print_heading(cli, state);
+                print_up_to_date();
+            }
+            Some(Status::UpToDate)
+        }
+    } else if let Some(output) = target.annotations.get("output") {
+        // Named target that produces a file (`{output=PATH}`): process it if `-B` or `--no-deps`,
+        // the output file doesn't exist, or any dependency is newer than it
+        let ts = mtime(output, !cli.no_follow_symlinks);
+        if cli.force_processing
+            || cli.no_deps
+            || !Path::new(output).exists()
+            || target
+                .inputs()
+                .any(|x| mtime(x, !cli.no_follow_symlinks) > ts)
+            || target
+                .dependencies
+                .iter()
+                .any(|x| targets.get(x).is_some_and(|x| x.outdated(&ts, targets)))
+        {
+            Some(target.process(cli, state))
+        } else {
+            if cli.verbose >= 2 && cli.verbose_uptodate {
+                target.print_heading(cli, state);
+                print_up_to_date();
+            }
+            Some(Status::UpToDate)
         }
     } else {
         // "Phony" target
-        target.run(dry_run, verbose, quiet, script_mode);
+        Some(target.process(cli, state))
     }
 }
 
-fn run(command: &str, dry_run: bool, quiet: bool) {
-    let results = Shell {
-        dry_run,
-        print: !quiet,
+fn run(command: Command, dry_run: bool, quiet: bool, nice: Option<i32>) -> Command {
+    shell(dry_run, quiet, nice).run(&[command]).remove(0)
+}
+
+/// Terminal color choice (`--color`) for the shell's printed commands
+static COLOR: OnceLock<ColorOverride> = OnceLock::new();
+
+/// Shell that prints commands with the `--color` choice and the `--theme` palette
+fn base_shell() -> Shell {
+    Shell {
+        color: COLOR.get().cloned().unwrap_or(ColorOverride::Auto),
+        fence_style: *FENCE,
+        info_style: *FENCE,
+        prompt_style: *FENCE,
+        command_style: *COMMAND,
+        error_style: *COMMAND_ERROR,
         ..Default::default()
     }
-    .run(&[Command::new(command)]);
+}
 
-    for result in &results {
-        exit_if_failed(result, dry_run);
+/// Shell for running commands, with reduced priority if `nice` is available (`--nice`)
+fn shell(dry_run: bool, quiet: bool, nice: Option<i32>) -> Shell {
+    let mut shell = Shell {
+        dry_run,
+        print: !quiet,
+        ..base_shell()
+    };
+    if let Some(prefix) = nice_prefix(nice) {
+        shell.shell = Some(format!("{prefix}sh -c"));
     }
+    shell
 }
 
-fn run_script(script: &str, dry_run: bool, verbose: u8, quiet: bool, shell: Option<String>) {
-    let command = if let Some(command) = &shell {
-        command
-    } else if verbose >= 1 {
-        "bash -xeo pipefail"
-    } else {
-        "bash -eo pipefail"
-    };
+/// Return the `nice -n N ` command prefix if requested and `nice` is available
+fn nice_prefix(nice: Option<i32>) -> Option<String> {
+    nice.filter(|_| program_exists("nice"))
+        .map(|nice| format!("nice -n {nice} "))
+}
+
+/// Run a command and capture its standard output
+fn capture(command: Command, dry_run: bool, quiet: bool, nice: Option<i32>) -> Command {
+    shell(dry_run, quiet, nice)
+        .run(&[Command {
+            stdout: Pipe::String(None),
+            ..command
+        }])
+        .remove(0)
+}
+
+/// Blank out single-quoted strings, where braces are shell syntax (`awk '{print}'`), not variables
+fn without_single_quoted(command: &str) -> String {
+    let (mut single, mut double) = (false, false);
+    command
+        .chars()
+        .map(|c| {
+            match c {
+                '\'' if !double => single = !single,
+                '"' if !single => double = !double,
+                _ if single => return ' ',
+                _ => {}
+            }
+            c
+        })
+        .collect()
+}
+
+/// Parse a `{run:NAME}` recipe line
+fn parse_run(command: &str) -> Option<&str> {
+    let name = command.trim().strip_prefix("{run:")?.strip_suffix('}')?;
+    Some(name.trim())
+}
+
+/// Parse a `NAME=VALUE` named parameter given among the targets on the command line
+fn parse_param(s: &str) -> Option<(&str, &str)> {
+    let (name, value) = s.split_once('=')?;
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    valid.then_some((name, value))
+}
+
+/// Parse a `{capture NAME}= COMMAND` recipe line
+fn parse_capture(command: &str) -> Option<(&str, &str)> {
+    let (name, command) = command.strip_prefix("{capture ")?.split_once("}=")?;
+    Some((name.trim(), command.trim()))
+}
+
+/// Command to run a script: the recipe's custom command or bash, with `-x` if verbose and `-l` for
+/// a login shell (`{login}`, `--login-shell`)
+fn script_shell(shell: Option<&str>, verbose: u8, login: bool) -> String {
+    if let Some(shell) = shell {
+        return shell.to_string();
+    }
+    let login = if login { "l" } else { "" };
+    let trace = if verbose >= 1 { "x" } else { "" };
+    format!("bash -{login}{trace}eo pipefail")
+}
 
-    let result = Shell {
+fn run_script(
+    script: &str,
+    dry_run: bool,
+    quiet: bool,
+    command: &str,
+    nice: Option<i32>,
+    base: Command,
+    tee: Option<(bool, bool)>,
+) -> Command {
+    let shell = Shell {
         dry_run,
         print: !quiet,
-        ..Default::default()
-    }
-    .core(&Command {
-        command: command.to_string(),
+        ..base_shell()
+    };
+    let command = Command {
+        command: format!("{}{command}", nice_prefix(nice).unwrap_or_default()),
         stdin: Pipe::String(Some(script.to_string())),
-        ..Default::default()
+        ..base
+    };
+    match tee {
+        Some(echo) => run_tee(&shell, &command, echo),
+        None => shell.core(&command),
+    }
+}
+
+/// Run a command or script like the shell does, copying its stdout and/or stderr to the terminal
+/// as it is produced, and return all of its output (`--log-dir`)
+fn run_tee(shell: &Shell, command: &Command, echo: (bool, bool)) -> Command {
+    // The shell prints a script itself
+    let script = matches!(command.stdin, Pipe::String(Some(_)));
+    if shell.print && !script {
+        shell.print_fence(0);
+        println!("{}", shell.info.style(shell.info_style));
+        print!("{}", shell.prompt.style(shell.prompt_style));
+        println!(
+            "{}",
+            command
+                .command
+                .replace(" && ", " \\\n&& ")
+                .replace(" || ", " \\\n|| ")
+                .replace("; ", "; \\\n")
+                .style(shell.command_style),
+        );
+    }
+
+    let mut child = shell.run1_async(&Command {
+        stdout: Pipe::String(None),
+        stderr: Pipe::String(None),
+        ..command.clone()
     });
+    let stdout = child.stdout.take().expect("stdout");
+    let stderr = child.stderr.take().expect("stderr");
+    let stdout = std::thread::spawn(move || copy_output(stdout, echo.0, false));
+    let stderr = std::thread::spawn(move || copy_output(stderr, echo.1, true));
+    let code = child.wait().ok().and_then(|x| x.code());
+    let r = Command {
+        stdout: Pipe::String(Some(stdout.join().expect("stdout"))),
+        stderr: Pipe::String(Some(stderr.join().expect("stderr"))),
+        code,
+        ..command.clone()
+    };
 
-    exit_if_failed(&result, dry_run);
+    if shell.print {
+        shell.print_fence(2);
+        let error = match r.code {
+            _ if script => None,
+            Some(code) if r.codes.contains(&code) => None,
+            Some(code) => Some(format!(
+                "**Command `{}` exited with code: `{code}`!**",
+                r.command
+            )),
+            None => Some(format!(
+                "**Command `{}` was killed by a signal!**",
+                r.command
+            )),
+        };
+        if let Some(error) = error {
+            println!("{}\n", error.style(shell.error_style));
+        }
+    }
+    r
 }
 
-fn exit_if_failed(result: &Command, dry_run: bool) {
-    if let Some(code) = &result.code {
-        if !result.codes.contains(code) {
-            std::process::exit(*code);
+/// Read a child process's output, copying it to the terminal as it is produced if echoing
+fn copy_output(mut pipe: impl std::io::Read, mut echo: bool, stderr: bool) -> String {
+    let mut output = vec![];
+    let mut buffer = [0; 8192];
+    while let Ok(n) = pipe.read(&mut buffer) {
+        if n == 0 {
+            break;
+        }
+        if echo {
+            let written = if stderr {
+                let mut x = std::io::stderr();
+                x.write_all(&buffer[..n]).and_then(|()| x.flush())
+            } else {
+                let mut x = std::io::stdout();
+                x.write_all(&buffer[..n]).and_then(|()| x.flush())
+            };
+            if written.is_err() {
+                echo = false;
+            }
         }
-    } else if !dry_run {
-        std::process::exit(1);
+        output.extend_from_slice(&buffer[..n]);
     }
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+/// Return a failure if the command exited with an unexpected code, or without an exit code (unless
+/// dry run)
+fn check_result(result: &Command, command: &str, dry_run: bool) -> Result<(), Failure> {
+    let code = match result.code {
+        Some(code) if result.codes.contains(&code) => return Ok(()),
+        Some(code) => code,
+        None if dry_run => return Ok(()),
+        None => 1,
+    };
+    Err(Failure {
+        command: command.to_string(),
+        code,
+    })
 }
 
 //--------------------------------------------------------------------------------------------------
 
+/// A failed command
 #[derive(Debug)]
-struct Config {
-    targets: IndexMap<String, Target>,
+struct Failure {
+    command: String,
+    code: i32,
 }
 
-impl Default for Config {
-    fn default() -> Config {
-        Config {
-            targets: IndexMap::new(),
-        }
-    }
+/// Status of a processed target
+#[derive(Debug)]
+enum Status {
+    Succeeded,
+    UpToDate,
+    Skipped,
+    Failed(Failure),
 }
 
-impl Config {
-    fn from(config_files: &[PathBuf]) -> Result<Config> {
-        let mut r = Config::default();
-        let dirname = std::env::current_dir()?
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string();
-        for config_file in config_files {
-            r.load(config_file, &dirname)?;
+impl From<Result<(), Failure>> for Status {
+    fn from(result: Result<(), Failure>) -> Status {
+        match result {
+            Ok(()) => Status::Succeeded,
+            Err(failure) => Status::Failed(failure),
         }
-        Ok(r)
     }
+}
 
-    fn load(&mut self, config_file: &Path, dirname: &str) -> Result<()> {
-        if config_file.exists() {
-            match std::fs::read_to_string(config_file) {
-                Ok(s) => {
-                    self.load_markdown(&s, dirname);
-                    Ok(())
-                }
-                Err(e) => Err(anyhow!("{e}")),
-            }
+/// Status, start (since the first target started), and duration of a processed target
+#[derive(Debug)]
+struct Outcome {
+    target: String,
+    status: Status,
+    started: Duration,
+    duration: Duration,
+}
+
+impl Outcome {
+    fn failure(&self) -> Option<&Failure> {
+        if let Status::Failed(failure) = &self.status {
+            Some(failure)
         } else {
-            Err(anyhow!(
-                "Configuration file `{}` does not exist!",
-                config_file.display(),
-            ))
+            None
         }
     }
 
-    fn load_markdown(&mut self, s: &str, dirname: &str) {
-        let mut in_h1 = false;
-        let mut in_dependencies = false;
-        let mut in_recipe = None;
+    fn to_json(&self) -> String {
+        let (status, code, command) = match &self.status {
+            Status::Succeeded => ("succeeded", String::from("0"), String::from("null")),
+            Status::UpToDate => ("up-to-date", String::from("null"), String::from("null")),
+            Status::Skipped => ("skipped", String::from("null"), String::from("null")),
+            Status::Failed(failure) => (
+                "failed",
+                failure.code.to_string(),
+                json_string(&failure.command),
+            ),
+        };
+        format!(
+            "{{\"name\": {}, \"status\": \"{status}\", \"duration\": {:.3}, \"code\": {code}, \
+            \"command\": {command}}}",
+            json_string(&self.target),
+            self.duration.as_secs_f64(),
+        )
+    }
+}
+
+/// Errors and warnings found in the configuration
+#[derive(Debug, Default)]
+struct Diagnostics {
+    errors: Vec<String>,
+    warnings: Vec<String>,
+}
+
+impl Diagnostics {
+    fn error(&mut self, message: String) {
+        self.errors.push(message);
+    }
+
+    fn warning(&mut self, message: String) {
+        self.warnings.push(message);
+    }
+
+    /// Return the errors, including the warnings if strict (`--strict`)
+    fn errors(self, strict: bool) -> Vec<String> {
+        let mut errors = self.errors;
+        if strict {
+            errors.extend(self.warnings);
+        }
+        errors
+    }
+
+    /// Print the diagnostics and return the number of errors, including the warnings if strict
+    /// (`--strict`)
+    fn report(self, strict: bool) -> usize {
+        if !strict {
+            for warning in &self.warnings {
+                ecprint!(*WARNING, "WARNING: {warning}!\n");
+            }
+        }
+        let errors = self.errors(strict);
+        for error in &errors {
+            ecprint!(*ERROR, "ERROR: {error}!\n");
+        }
+        errors.len()
+    }
+}
+
+/// Mutable state of a run
+#[derive(Debug, Default)]
+struct State {
+    commands: Vec<Executed>,
+    exports: IndexMap<String, String>,
+    problems: Vec<(i32, String)>,
+    processed: HashSet<String>,
+    once: HashSet<String>,
+    progress: (usize, usize),
+    output: String,
+}
+
+impl State {
+    /// Return the `[n/total]` progress prefix for target headings, if processing more than one
+    /// target
+    fn progress(&self) -> String {
+        let (n, total) = self.progress;
+        if total > 1 {
+            format!("[{n}/{total}] ")
+        } else {
+            String::new()
+        }
+    }
+
+    /// Exit with a problem, or collect it to report at the end of a dry run (`-n`)
+    fn problem(&mut self, cli: &Cli, code: i32, message: String) {
+        if cli.dry_run {
+            self.problems.push((code, message));
+        } else {
+            error!(code, "ERROR: {message}!");
+        }
+    }
+
+    /// Print a warning, or report it as a problem if warnings are errors (`--strict`)
+    fn warning(&mut self, cli: &Cli, message: String) {
+        if cli.strict {
+            self.problem(cli, 13, message);
+        } else {
+            ecprint!(*WARNING, "WARNING: {message}!\n");
+        }
+    }
+
+    /// Read the `key=value` lines a target's recipes wrote to its export file as `{target.key}`
+    /// values
+    fn import(&mut self, target: &str, path: &Path) {
+        let Ok(s) = std::fs::read_to_string(path) else {
+            return;
+        };
+        for line in s.lines() {
+            if let Some((key, value)) = line.split_once('=') {
+                self.exports
+                    .insert(format!("{target}.{}", key.trim()), value.trim().to_string());
+            }
+        }
+    }
+
+    /// Substitute `{target.key}` values exported by previous targets and `{NAME}` values captured
+    /// by previous commands
+    fn substitute(&self, command: &str) -> String {
+        let mut command = command.to_string();
+        for (key, value) in &self.exports {
+            command = command.replace(&format!("{{{key}}}"), value);
+        }
+        command
+    }
+
+    fn record(&mut self, target: &str, command: &str, result: &Command, duration: Duration) {
+        self.commands.push(Executed {
+            target: target.to_string(),
+            command: command.to_string(),
+            code: result.code,
+            duration,
+        });
+    }
+
+    /// Print a table of the executed commands
+    fn print_commands(&self) {
+        cprint!(*CONFIGURATION, "# Commands\n\n");
+        println!("Target | Command | Code | Duration");
+        println!("-------|---------|------|---------");
+        for x in &self.commands {
+            let code = x
+                .code
+                .map(|x| x.to_string())
+                .unwrap_or_else(|| String::from("-"));
+            println!(
+                "{} | {} | {code} | {:.3}s",
+                x.target.replace('|', "\\|"),
+                table_cell(&x.command),
+                x.duration.as_secs_f64(),
+            );
+        }
+        println!();
+    }
+}
+
+/// Print a table of files with their sizes and modification times (`--list-produced`)
+fn print_produced(files: &[&str]) {
+    cprint!(*CONFIGURATION, "# Produced files\n\n");
+    println!("File | Size | Modified");
+    println!("-----|------|---------");
+    let now = std::time::SystemTime::now();
+    for file in files {
+        let Ok(metadata) = std::fs::metadata(file) else {
+            println!("{} | - | -", file.replace('|', "\\|"));
+            continue;
+        };
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|x| now.duration_since(x).ok())
+            .unwrap_or_default();
+        println!(
+            "{} | {} B | {:.1}s ago",
+            file.replace('|', "\\|"),
+            metadata.len(),
+            age.as_secs_f64(),
+        );
+    }
+    println!();
+}
+
+/// An executed command, its exit code, and duration
+#[derive(Debug)]
+struct Executed {
+    target: String,
+    command: String,
+    code: Option<i32>,
+    duration: Duration,
+}
+
+/// Truncate a command to its first line and 40 characters and escape pipes for a Markdown table
+fn table_cell(command: &str) -> String {
+    let mut cell = command
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .take(40)
+        .collect::<String>();
+    if cell.len() < command.len() {
+        cell.push('…');
+    }
+    cell.replace('|', "\\|")
+}
+
+/// Version, features (command line options and annotations), shells, and platform as JSON
+/// (`--version --json`)
+fn version_json() -> String {
+    let cli = Cli::command();
+    let mut features = cli
+        .get_arguments()
+        .filter(|x| !x.is_positional())
+        .map(|x| {
+            x.get_long()
+                .map_or_else(|| x.get_id().to_string().replace('_', "-"), str::to_string)
+        })
+        .filter(|x| !["help", "json", "version"].contains(&x.as_str()))
+        .chain(ANNOTATIONS.iter().map(|x| x.to_string()))
+        .collect::<Vec<_>>();
+    features.sort_unstable();
+    features.dedup();
+    format!(
+        "{{\"version\":{},\"features\":[{}],\"shell\":{},\"script_shell\":{},\
+        \"platform\":{}}}",
+        json_string(env!("CARGO_PKG_VERSION")),
+        features
+            .iter()
+            .map(|x| json_string(x))
+            .collect::<Vec<_>>()
+            .join(","),
+        json_string(base_shell().shell.as_deref().unwrap_or_default()),
+        json_string(&script_shell(None, 0, false)),
+        json_string(&format!(
+            "{}-{}",
+            std::env::consts::ARCH,
+            std::env::consts::OS
+        )),
+    )
+}
+
+/// Write a JSON report of the processed targets (`--report json=PATH`)
+fn write_report(path: &Path, outcomes: &[Outcome]) -> Result<()> {
+    let success = outcomes.iter().all(|x| x.failure().is_none());
+    let targets = outcomes
+        .iter()
+        .map(|x| format!("    {}", x.to_json()))
+        .collect::<Vec<_>>()
+        .join(",\n");
+    std::fs::write(
+        path,
+        format!("{{\n  \"success\": {success},\n  \"targets\": [\n{targets}\n  ]\n}}\n"),
+    )?;
+    Ok(())
+}
+
+/// Write the processed targets' begin and end times in the Chrome trace event format
+/// (`--trace-file PATH`); targets run one at a time on the same thread
+fn write_trace(path: &Path, outcomes: &[Outcome]) -> Result<()> {
+    let pid = std::process::id();
+    let events = outcomes
+        .iter()
+        .flat_map(|x| {
+            let name = json_string(&x.target);
+            let begin = x.started.as_micros();
+            let end = (x.started + x.duration).as_micros();
+            [("B", begin), ("E", end)].map(|(phase, ts)| {
+                format!(
+                    "    {{\"name\": {name}, \"cat\": \"target\", \"ph\": \"{phase}\", \"ts\": {ts}, \
+                    \"pid\": {pid}, \"tid\": 1}}"
+                )
+            })
+        })
+        .collect::<Vec<_>>()
+        .join(",\n");
+    std::fs::write(
+        path,
+        format!("{{\n  \"traceEvents\": [\n{events}\n  ]\n}}\n"),
+    )?;
+    Ok(())
+}
+
+//--------------------------------------------------------------------------------------------------
+
+/// Gitignore-style patterns for paths excluded from glob dependencies (`.mkrsignore`)
+#[derive(Debug, Default)]
+struct Ignore(Vec<(GlobMatcher, bool)>);
+
+impl Ignore {
+    /// Load the patterns from a file, if it exists
+    ///
+    /// A pattern without a slash matches a file or directory at any depth; a pattern with a slash
+    /// is relative to the current directory; a trailing slash is ignored, and a leading `!`
+    /// re-includes paths excluded by an earlier pattern. Invalid patterns are added to the
+    /// warnings.
+    fn load(path: &Path, warnings: &mut Vec<String>) -> Ignore {
+        let Ok(s) = std::fs::read_to_string(path) else {
+            return Ignore::default();
+        };
+        let mut patterns = vec![];
+        for line in s.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (pattern, negated) = match line.strip_prefix('!') {
+                Some(pattern) => (pattern, true),
+                None => (line, false),
+            };
+            let pattern = pattern.trim_end_matches('/');
+            let pattern = if pattern.contains('/') {
+                pattern.trim_start_matches('/').to_string()
+            } else {
+                format!("**/{pattern}")
+            };
+
+            // Match the path itself or anything in it
+            let glob = GlobBuilder::new(&format!("{{{pattern},{pattern}/**}}"))
+                .literal_separator(true)
+                .build();
+            match glob {
+                Ok(glob) => patterns.push((glob.compile_matcher(), negated)),
+                Err(e) => {
+                    warnings.push(format!(
+                        "Invalid pattern `{line}` in `{}`: {e}",
+                        path.display()
+                    ));
+                }
+            }
+        }
+        Ignore(patterns)
+    }
+
+    /// Return true if the last pattern matching the path excludes it
+    fn is_ignored(&self, path: &str) -> bool {
+        let path = path.strip_prefix("./").unwrap_or(path);
+        self.0
+            .iter()
+            .rev()
+            .find(|(matcher, _)| matcher.is_match(path))
+            .is_some_and(|(_, negated)| !negated)
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+
+const REGION_START: &str = "<!-- mkrs:start -->";
+const REGION_END: &str = "<!-- mkrs:end -->";
+
+#[derive(Debug)]
+struct Config {
+    targets: IndexMap<String, Target>,
+    files: Vec<PathBuf>,
+    heading_level: pd::HeadingLevel,
+    config_dir: ConfigDir,
+    no_substitution: bool,
+    ignore: Ignore,
+    warnings: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            targets: IndexMap::new(),
+            files: vec![],
+            heading_level: pd::HeadingLevel::H1,
+            config_dir: ConfigDir::Cwd,
+            no_substitution: false,
+            ignore: Ignore::default(),
+            warnings: vec![],
+        }
+    }
+}
+
+impl Config {
+    fn from(cli: &Cli) -> Result<Config> {
+        let mut warnings = vec![];
+        let mut r = Config {
+            heading_level: pd::HeadingLevel::try_from(cli.heading_level as usize)
+                .expect("heading level"),
+            config_dir: cli.config_dir,
+            no_substitution: cli.no_substitution,
+            ignore: Ignore::load(Path::new(".mkrsignore"), &mut warnings),
+            ..Default::default()
+        };
+        r.warnings = warnings;
+        let dirname = std::env::current_dir()?
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        for config_file in &cli.config_files {
+            let source = config_file.display().to_string();
+            if is_remote(&source) {
+                if !cli.allow_remote {
+                    return Err(anyhow!(
+                        "Remote configuration `{source}` requires `--allow-remote`!"
+                    ));
+                }
+
+                // File paths and includes in a remote configuration are relative to the current
+                // directory
+                let s = fetch(&source)?;
+                for include in r.load_markdown(&s, Path::new(&source), &dirname, None) {
+                    r.load(Path::new(&include), &dirname)?;
+                }
+            } else if source.contains(['*', '?', '[']) {
+                // Load all matching configuration files in sorted order (`-f 'tasks/*.md'`); a
+                // file matched more than once is only loaded once
+                let mut paths = glob(&source)?.filter_map(|x| x.ok()).collect::<Vec<_>>();
+                if paths.is_empty() {
+                    return Err(anyhow!("No configuration files match `{source}`!"));
+                }
+                paths.sort();
+                for path in paths {
+                    r.load(&path, &dirname)?;
+                }
+            } else {
+                r.load(config_file, &dirname)?;
+            }
+        }
+
+        // Add phony targets matching a pattern as dependencies (`{deps-glob=PATTERN}`)
+        r.add_glob_dependencies();
+
+        // Map code block info strings to commands (`# .SHELLS {py="python3 -u"}`)
+        r.map_shells();
+
+        // Inline other targets' recipes (`{run:NAME}`)
+        let names = r.targets.keys().cloned().collect::<Vec<_>>();
+        for name in names {
+            let recipes = r.inline_recipes(&name, &mut vec![]);
+            r.targets.get_mut(&name).unwrap().recipes = recipes;
+        }
+
+        // Add dependencies discovered by targets' scanner commands (`{deps-command=...}`,
+        // `--allow-dynamic-deps`)
+        if cli.allow_dynamic_deps {
+            r.add_dynamic_dependencies();
+        }
+
+        // Use symlinks' own modification times (`--no-follow-symlinks`)
+        if cli.no_follow_symlinks {
+            for target in r.targets.values_mut() {
+                if target.dtg.is_some() {
+                    target.dtg = Some(mtime(&target.name, false));
+                }
+            }
+        }
+
+        // Treat changed files as if just modified (`--changed`)
+        let now = std::time::SystemTime::now();
+        for path in &cli.changed {
+            if let Some(target) = r.targets.get_mut(path) {
+                if target.dtg.is_some() {
+                    target.dtg = Some(now);
+                }
+            }
+        }
+
+        Ok(r)
+    }
+
+    fn load(&mut self, config_file: &Path, dirname: &str) -> Result<()> {
+        // Already loaded (avoid include cycles), compared by canonical path so that
+        // `./common.md` and `sub/../common.md` are the same file as `common.md`
+        let canonical =
+            std::fs::canonicalize(config_file).unwrap_or_else(|_| config_file.to_path_buf());
+        if self.files.contains(&canonical) {
+            return Ok(());
+        }
+        if config_file.exists() {
+            match std::fs::read_to_string(config_file) {
+                Ok(s) => {
+                    self.files.push(canonical);
+                    let dir = config_file.parent().unwrap_or(Path::new(""));
+
+                    // Anchor file paths and `{dirname}` to the configuration file's directory
+                    // (`--config-dir config`)
+                    let (base, anchored_dirname) =
+                        if self.config_dir == ConfigDir::Config && !dir.as_os_str().is_empty() {
+                            let anchored_dirname = std::fs::canonicalize(dir)?
+                                .file_name()
+                                .map(|x| x.to_string_lossy().to_string())
+                                .unwrap_or_default();
+                            (Some(dir), anchored_dirname)
+                        } else {
+                            (None, dirname.to_string())
+                        };
+
+                    for include in self.load_markdown(&s, config_file, &anchored_dirname, base) {
+                        self.load(&dir.join(include), dirname)?;
+                    }
+                    Ok(())
+                }
+                Err(e) => Err(anyhow!("{e}")),
+            }
+        } else {
+            Err(anyhow!(
+                "Configuration file `{}` does not exist!",
+                config_file.display(),
+            ))
+        }
+    }
+
+    /// Add a target defined in a configuration file
+    ///
+    /// A target already defined in a configuration file is replaced by one annotated `{override}`
+    /// or gets the dependencies and recipes of one annotated `{extend}`; otherwise it is an error.
+    fn add_target(&mut self, target: Target) {
+        let Some(existing) = self.targets.get_mut(&target.name) else {
+            self.targets.insert(target.name.clone(), target);
+            return;
+        };
+        if existing.source.as_os_str().is_empty() || target.annotations.has("override") {
+            // Replace a file target that was only mentioned as a dependency
+            *existing = target;
+        } else if target.annotations.has("extend") {
+            for dependency in target.dependencies {
+                if !existing.dependencies.contains(&dependency) {
+                    existing.dependencies.push(dependency);
+                }
+            }
+            existing.patterns.extend(target.patterns);
+            existing.recipes.extend(target.recipes);
+            if existing.description.is_empty() {
+                existing.description = target.description;
+            }
+        } else {
+            error!(
+                19,
+                "ERROR: Target `{}` in `{}` is already defined in `{}`; use `{{override}}` or \
+                `{{extend}}`!",
+                target.name,
+                target.source.display(),
+                existing.source.display(),
+            );
+        }
+    }
+
+    /// Load targets from Markdown and return the paths of any included configuration files
+    fn load_markdown(
+        &mut self,
+        s: &str,
+        source: &Path,
+        dirname: &str,
+        base: Option<&Path>,
+    ) -> Vec<String> {
+        let heading_level = self.heading_level;
+        let mut includes = vec![];
+        let mut in_heading = false;
+        let mut in_dependencies = false;
+        let mut in_description = false;
+        let mut in_recipe: Option<(Option<String>, Annotations)> = None;
         let mut is_file = false;
         let mut is_glob = false;
         let mut name: Option<String> = None;
+        let mut heading_text = String::new();
+        let mut annotations = Annotations::default();
+        let mut description = String::new();
         let mut dependencies = vec![];
+        let mut patterns = vec![];
         let mut recipes = vec![];
-        for event in pd::Parser::new_ext(s, pd::Options::all()) {
+
+        // If the document has `<!-- mkrs:start -->` / `<!-- mkrs:end -->` markers, only parse the
+        // regions between them
+        let mut in_region = !s.contains(REGION_START);
+
+        // Heading attributes are disabled so that `{...}` annotations are kept in the heading text,
+        // and smart punctuation so that annotation values like `{args-default=--quick}` are kept
+        // verbatim
+        let options = pd::Options::all()
+            - pd::Options::ENABLE_HEADING_ATTRIBUTES
+            - pd::Options::ENABLE_SMART_PUNCTUATION;
+
+        for event in pd::Parser::new_ext(s, options) {
+            if let pd::Event::Html(html) | pd::Event::InlineHtml(html) = &event {
+                match html.trim() {
+                    REGION_START => in_region = true,
+                    REGION_END => in_region = false,
+                    _ => {}
+                }
+            }
+            if !in_region {
+                continue;
+            }
+
             match event {
-                pd::Event::Start(pd::Tag::Heading {
-                    level: pd::HeadingLevel::H1,
-                    ..
-                }) => {
+                pd::Event::Start(pd::Tag::Heading { level, .. }) if level == heading_level => {
                     if let Some(n) = name.take() {
                         // Push staged target
-                        let target = Target::new(
+                        let mut target = Target::new(
                             &n,
                             is_file,
                             glob_matcher(&n, is_glob),
                             &dependencies,
                             std::mem::take(&mut recipes),
                         );
-                        self.targets.insert(n, target);
+                        target.patterns = std::mem::take(&mut patterns);
+                        target.annotations = std::mem::take(&mut annotations);
+                        target.description = std::mem::take(&mut description);
+                        target.source = source.to_path_buf();
+                        self.add_target(target);
 
                         // Reset
                         name = None;
@@ -461,39 +1844,69 @@ impl Config {
                         is_glob = false;
                         dependencies = vec![];
                     }
-                    in_h1 = true;
+                    heading_text.clear();
+                    annotations = Annotations::default();
+                    in_heading = true;
                 }
                 pd::Event::Code(s) => {
-                    let s = s.replace("{dirname}", dirname);
-                    if in_h1 {
+                    if in_heading {
+                        let s = s.replace("{dirname}", dirname);
                         if s.starts_with("*.") && s.len() > 2 {
                             is_glob = true;
                             name = Some(s);
                         } else {
                             is_file = true;
-                            name = Some(s);
+                            name = Some(anchor(&s, base));
                         }
                     } else if in_dependencies {
-                        let s = expanduser(&s).unwrap().display().to_string();
+                        // A quoted dependency is a literal path, not a glob (`"[draft].md"`)
+                        if let Some(literal) = s.strip_prefix('"').and_then(|x| x.strip_suffix('"'))
+                        {
+                            dependencies.push(anchor(&expand_path(literal, dirname), base));
+                            continue;
+                        }
+
+                        let s = expand_path(&s, dirname);
+                        let s = if is_glob { s } else { anchor(&s, base) };
                         let mut globbed = glob(&s)
                             .expect("glob")
                             .filter_map(|x| x.map(|x| x.display().to_string()).ok())
                             .collect::<Vec<_>>();
-                        if globbed.is_empty() || is_glob {
+                        let matched = !globbed.is_empty();
+
+                        // Exclude paths matched by a glob pattern but ignored (`.mkrsignore`)
+                        if s.contains(['*', '?', '[']) {
+                            globbed.retain(|x| !self.ignore.is_ignored(x));
+                        }
+
+                        if !matched || is_glob {
                             dependencies.push(s.to_string());
                         } else {
+                            if s.contains(['*', '?', '[']) {
+                                patterns.push((s.to_string(), globbed.clone()));
+                            }
                             dependencies.append(&mut globbed);
                         }
+                    } else if in_description {
+                        description.push_str(&s.replace("{dirname}", dirname));
                     }
                 }
                 pd::Event::Text(s) => {
-                    if in_h1 {
-                        is_file = false;
-                        name = Some(s.to_string());
+                    if in_heading {
+                        heading_text.push_str(&s);
                     } else if in_dependencies {
-                        dependencies.push(s.to_string());
-                    } else if let Some(shell) = in_recipe.take() {
-                        let s = if is_glob {
+                        let s = s.trim();
+                        if !s.is_empty() {
+                            dependencies.push(s.to_string());
+                        }
+                    } else if in_description {
+                        description.push_str(&s);
+                    } else if let Some((shell, annotations)) = in_recipe.take() {
+                        // Don't substitute variables in a literal recipe (`{literal}`) or any recipe
+                        // (`--no-substitution`)
+                        let literal = annotations.has("literal") || self.no_substitution;
+
+                        let s = if is_glob || literal {
                             s.trim().to_string()
                         } else {
                             s.trim()
@@ -501,17 +1914,18 @@ impl Config {
                                 .replace("{dirname}", dirname)
                         };
 
-                        let s = if dependencies.is_empty() || is_glob {
+                        let s = if dependencies.is_empty() || is_glob || literal {
                             s
                         } else {
                             s.replace("{0}", &dependencies[0])
                         };
 
-                        if let Some(shell) = shell {
-                            recipes.push(Recipe::new(Some(shell), vec![s]));
+                        let mut recipe = if let Some(shell) = shell {
+                            Recipe::new(Some(shell), &annotations, vec![s])
                         } else {
-                            recipes.push(Recipe::new(
+                            Recipe::new(
                                 None,
+                                &annotations,
                                 s.replace("\\\n", "")
                                     .lines()
                                     .filter_map(|x| {
@@ -522,12 +1936,43 @@ impl Config {
                                         }
                                     })
                                     .collect(),
-                            ));
-                        }
+                            )
+                        };
+
+                        // Redirected output paths are relative to the configuration file's
+                        // directory if anchored there (`--config-dir config`)
+                        recipe.literal = literal;
+                        recipe.stdout = recipe.stdout.map(|x| anchor(&x, base));
+                        recipe.stderr = recipe.stderr.map(|x| anchor(&x, base));
+                        recipes.push(recipe);
+                    }
+                }
+                pd::Event::End(pd::TagEnd::Heading(level)) if level == heading_level => {
+                    // Split `{...}` annotations from the heading text; any remaining text names a
+                    // phony target
+                    let (text, parsed) = Annotations::parse(&heading_text);
+                    if name.is_none() && !text.is_empty() {
+                        is_file = false;
+                        name = Some(text);
                     }
+                    annotations = parsed;
+                    in_heading = false;
+                }
+                pd::Event::Start(pd::Tag::Paragraph)
+                    if name.is_some() && !in_dependencies && description.is_empty() =>
+                {
+                    // First paragraph after a target heading is its description
+                    in_description = true;
+                }
+                pd::Event::End(pd::TagEnd::Paragraph) => {
+                    in_description = false;
+                }
+                pd::Event::SoftBreak | pd::Event::HardBreak if in_description => {
+                    description.push(' ');
                 }
-                pd::Event::End(pd::TagEnd::Heading(pd::HeadingLevel::H1, ..)) => {
-                    in_h1 = false;
+                pd::Event::TaskListMarker(_) => {
+                    // The checkbox of a task list item (`- [ ] name`, `- [x] name`) is not part of
+                    // the dependency name
                 }
                 pd::Event::Start(pd::Tag::List(None)) => {
                     in_dependencies = true;
@@ -536,219 +1981,1686 @@ impl Config {
                     in_dependencies = false;
                 }
                 pd::Event::Start(pd::Tag::CodeBlock(pd::CodeBlockKind::Fenced(info))) => {
-                    let info = info.to_string();
-                    in_recipe = if info.is_empty() {
-                        Some(None)
+                    let (info, recipe_annotations) = Annotations::parse(&info);
+
+                    // A code block without a custom command inherits the target's shell
+                    // (`{shell=...}` heading annotation)
+                    let shell = if info.is_empty() {
+                        annotations.get("shell").map(String::from)
                     } else {
-                        Some(Some(info))
+                        Some(info)
                     };
+                    in_recipe = Some((shell, recipe_annotations));
                 }
                 pd::Event::End(pd::TagEnd::CodeBlock) => {
                     in_recipe = None;
                 }
+                pd::Event::Html(s) | pd::Event::InlineHtml(s) => {
+                    if let Some(include) = parse_include(&s) {
+                        includes.push(include);
+                    }
+                }
                 _ => {}
             }
         }
 
         // Add the last target
         if let Some(n) = name.take() {
-            let target = Target::new(
+            let mut target = Target::new(
                 &n,
                 is_file,
                 glob_matcher(&n, is_glob),
                 &dependencies,
                 recipes,
             );
-            self.targets.insert(n, target);
+            target.patterns = patterns;
+            target.annotations = annotations;
+            target.description = description;
+            target.source = source.to_path_buf();
+            self.add_target(target);
         }
 
         // Add files mentioned as dependencies but not targets in configuration
         let mut file_targets = vec![];
+        let mut seen = HashSet::new();
         for target in self.targets.values() {
             for dependency in &target.dependencies {
-                if !self.targets.contains_key(dependency) {
-                    file_targets.push((
-                        dependency.clone(),
-                        Target::new(dependency, true, None, &[], vec![]),
-                    ));
+                if !self.targets.contains_key(dependency) && seen.insert(dependency) {
+                    file_targets.push(dependency.clone());
                 }
             }
         }
-        for (name, target) in file_targets {
+        for name in file_targets {
+            let target = Target::new(&name, true, None, &[], vec![]);
             self.targets.insert(name, target);
         }
+
+        includes
     }
 
-    fn process(&mut self, cli: &Cli) -> Result<()> {
-        if cli.verbose >= 3 {
-            print_fence();
-            println!("\n{self:#?}");
-            print_end_fence();
+    /// Find errors (unresolved `{...}` variables) and warnings (recipes that do nothing) in the
+    /// configuration, or only in the given targets
+    fn check(&self, cli: &Cli, targets: Option<&[String]>) -> Diagnostics {
+        // Shell state carries over between the commands of a recipe run as a script (`-s`,
+        // `--one-shell`, `--login-shell`)
+        let script = cli.script_mode || cli.one_shell || cli.login_shell;
+        let mut diagnostics = Diagnostics::default();
+        if targets.is_none() {
+            for warning in &self.warnings {
+                diagnostics.warning(warning.clone());
+            }
         }
-
-        // List targets (`-l`)
-        if cli.list_targets {
-            if cli.targets.is_empty() {
-                for target in self.targets.values() {
-                    if target.dtg.is_none()
-                        || !target.dependencies.is_empty()
-                        || !target.recipes.is_empty()
-                    {
-                        if target.dtg.is_some() {
-                            print_list_file_target(&target.name, 0);
-                        } else {
-                            print_list_target(&target.name, 0);
-                        }
-                    }
+        let captures = self
+            .targets
+            .values()
+            .flat_map(|x| &x.recipes)
+            .flat_map(|x| &x.commands)
+            .filter_map(|x| parse_capture(x).map(|(name, _)| name))
+            .chain(
+                self.targets
+                    .values()
+                    .flat_map(|x| x.params().map(|(name, _)| name)),
+            )
+            .collect::<HashSet<_>>();
+        for target in self
+            .targets
+            .values()
+            .filter(|x| targets.is_none_or(|targets| targets.contains(&x.name)))
+        {
+            for recipe in &target.recipes {
+                if recipe.is_noop() {
+                    diagnostics.warning(format!(
+                        "Recipe for target `{}` is empty or only has comments",
+                        target.name
+                    ));
                 }
-            } else {
-                for target in &cli.targets {
-                    if !self.targets.contains_key(target) {
-                        error!(5, "ERROR: Invalid target: `{target}`!");
-                    }
+                for command in recipe.lost_state().into_iter().filter(|_| !script) {
+                    diagnostics.warning(format!(
+                        "Recipe for target `{}` runs `{command}` in its own shell, so it does not \
+                        affect the following commands; use `&&`, script mode, or a custom command",
+                        target.name
+                    ));
                 }
-                for target in &cli.targets {
-                    print_list_file_targets(target, &self.targets, 0);
+
+                // Wildcard target recipes are substituted when a matching target is generated
+                let fixed;
+                let recipe = if target.glob.is_some() {
+                    fixed = recipe.fix("target", "dependency");
+                    &fixed
+                } else {
+                    recipe
+                };
+                for token in recipe.unresolved(&self.targets, &captures) {
+                    diagnostics.error(format!(
+                        "Unresolved `{token}` in recipe for target `{}`",
+                        target.name
+                    ));
                 }
             }
-            println!();
-            return Ok(());
         }
+        diagnostics
+    }
 
-        // Which target(s) are we processing?
-        let targets = if cli.targets.is_empty() {
-            // First target in `Makefile.md`
-            vec![self.targets[0].name.clone()]
-        } else {
-            // Target(s) specified on the command line
-            cli.targets.clone()
-        };
-
-        // Process the target(s)
-        let mut processed = HashSet::new();
-        for target in &targets {
-            // Generate target from wildcard/glob target
-            if !self.targets.contains_key(target) {
-                for (_, t) in &self.targets {
-                    if let Some(glob) = t.glob.as_ref() {
-                        if glob.is_match(target) {
-                            let re = Regex::new(&format!("{}$", &t.name[2..])).expect("regex");
-                            let extension = &t.dependencies[0][2..];
-                            let dependency = re.replace(target, extension).to_string();
-                            let target_does_not_exist = !Path::new(target).exists();
-                            if cli.force_processing
-                                || target_does_not_exist
-                                || outdated(&dependency, target)
-                            {
-                                let t = Target::new(
-                                    target,
-                                    true,
-                                    None,
-                                    &[dependency.clone()],
-                                    t.recipes
-                                        .iter()
-                                        .map(|x| x.fix(target, &dependency))
-                                        .collect(),
-                                );
-                                self.targets.insert(target.clone(), t);
-                            }
-                            break;
-                        }
-                    }
+    /// Add the phony targets whose names match a target's `{deps-glob=PATTERN}` annotation to its
+    /// dependencies, in configuration order
+    fn add_glob_dependencies(&mut self) {
+        let phony = self
+            .targets
+            .values()
+            .filter(|x| x.is_phony())
+            .map(|x| x.name.clone())
+            .collect::<Vec<_>>();
+        for target in self.targets.values_mut() {
+            let Some(pattern) = target.annotations.get("deps-glob") else {
+                continue;
+            };
+            let Ok(glob) = Glob::new(pattern) else {
+                error!(
+                    5,
+                    "ERROR: Invalid `{{deps-glob={pattern}}}` for target `{}`!", target.name
+                );
+            };
+            let matcher = glob.compile_matcher();
+            for name in &phony {
+                if name != &target.name
+                    && matcher.is_match(name.as_str())
+                    && !target.dependencies.contains(name)
+                {
+                    target.dependencies.push(name.clone());
                 }
             }
+        }
+    }
 
-            let mut nodes = vec![];
-            add_node_and_deps(
-                target,
-                self,
-                &mut nodes,
-                &mut processed,
-                cli.force_processing,
-                None,
-            );
-            let num_nodes = nodes.len();
-            if num_nodes > 1 {
-                DepGraph::new(&nodes).into_iter().for_each(|x| {
-                    process_target(
-                        &x,
-                        &self.targets,
-                        cli.dry_run,
-                        cli.force_processing,
-                        cli.verbose,
-                        cli.quiet,
-                        cli.script_mode,
-                    );
-                });
-            } else if num_nodes > 0 {
-                process_target(
-                    nodes[0].id(),
-                    &self.targets,
-                    cli.dry_run,
-                    cli.force_processing,
-                    cli.verbose,
-                    cli.quiet,
-                    cli.script_mode,
-                );
+    /// Replace the first word of each recipe's custom command with the command mapped to it by the
+    /// `.SHELLS` special target's annotations, for instance, `# .SHELLS {py="python3 -u" js=node}`
+    fn map_shells(&mut self) {
+        let Some(shells) = self.targets.get(".SHELLS") else {
+            return;
+        };
+        let shells = shells
+            .annotations
+            .0
+            .iter()
+            .filter_map(|(key, value)| Some((key.clone(), value.clone()?)))
+            .collect::<Vec<_>>();
+        for recipe in self.targets.values_mut().flat_map(|x| &mut x.recipes) {
+            let Some(shell) = &recipe.shell else {
+                continue;
+            };
+            let (program, rest) = shell.split_once(' ').unwrap_or((shell, ""));
+            if let Some((_, command)) = shells.iter().find(|(key, _)| key == program) {
+                recipe.shell = Some(format!("{command} {rest}").trim_end().to_string());
             }
         }
-
-        Ok(())
     }
-}
 
-//--------------------------------------------------------------------------------------------------
+    /// Return a target's recipes with each `{run:NAME}` command line replaced by target `NAME`'s
+    /// recipes, which run at that point in the calling recipe's stage
+    fn inline_recipes<'a>(&'a self, name: &'a str, stack: &mut Vec<&'a str>) -> Vec<Recipe> {
+        if stack.contains(&name) {
+            error!(
+                18,
+                "ERROR: Recursive `{{run:{name}}}` in target `{}`!",
+                stack.last().unwrap(),
+            );
+        }
+        stack.push(name);
+        let target = self.targets.get(name).unwrap();
+        let mut r = vec![];
+        for recipe in &target.recipes {
+            // Keep recipes without `{run:NAME}` lines as is, including empty ones (`--check`)
+            if recipe.shell.is_some()
+                || recipe.literal
+                || !recipe.commands.iter().any(|x| parse_run(x).is_some())
+            {
+                r.push(recipe.clone());
+                continue;
+            }
+            let mut commands = vec![];
+            for command in &recipe.commands {
+                let Some(other) = parse_run(command) else {
+                    commands.push(command.clone());
+                    continue;
+                };
+                if !self.targets.contains_key(other) {
+                    error!(5, "ERROR: Invalid target in `{command}`: `{other}`!");
+                }
+                if !commands.is_empty() {
+                    r.push(Recipe {
+                        commands: std::mem::take(&mut commands),
+                        ..recipe.clone()
+                    });
+                }
+                let inlined = self.inline_recipes(other, stack);
+                for stage in [Stage::Setup, Stage::Main, Stage::Teardown] {
+                    for x in inlined
+                        .iter()
+                        .filter(|x| x.stage == stage && x.profile.is_none() && x.alt.is_none())
+                    {
+                        r.push(Recipe {
+                            stage: recipe.stage,
+                            profile: recipe.profile.clone(),
+                            alt: recipe.alt.clone(),
+                            ..x.clone()
+                        });
+                    }
+                }
+            }
+            if !commands.is_empty() {
+                r.push(Recipe {
+                    commands,
+                    ..recipe.clone()
+                });
+            }
+        }
+        stack.pop();
+        r
+    }
 
-#[derive(Debug)]
+    /// Run each target's scanner command (`{deps-command=...}`) and add the files in its make-style
+    /// output (`target: dependency...`) to the target's dependencies
+    fn add_dynamic_dependencies(&mut self) {
+        let mut file_targets = vec![];
+        for target in self.targets.values_mut() {
+            let Some(command) = target.annotations.get("deps-command") else {
+                continue;
+            };
+            let result = Shell {
+                print: false,
+                ..base_shell()
+            }
+            .run(&[Command {
+                stdout: Pipe::String(None),
+                ..Command::new(command)
+            }])
+            .remove(0);
+            let (Some(0), Pipe::String(Some(stdout))) = (result.code, &result.stdout) else {
+                error!(
+                    15,
+                    "ERROR: Dependency scanner for target `{}` failed!", target.name
+                );
+            };
+            let stdout = stdout.replace("\\\n", " ");
+            for line in stdout.lines() {
+                let dependencies = line.split_once(':').map_or(line, |(_, x)| x);
+                for dependency in dependencies.split_whitespace() {
+                    if !target.dependencies.iter().any(|x| x == dependency) {
+                        target.dependencies.push(dependency.to_string());
+                        file_targets.push(dependency.to_string());
+                    }
+                }
+            }
+        }
+        for name in file_targets {
+            if !self.targets.contains_key(&name) {
+                let target = Target::new(&name, true, None, &[], vec![]);
+                self.targets.insert(name, target);
+            }
+        }
+    }
+
+    /// Serialize the configuration as normalized markdown, or, given the CLI options, the effective
+    /// configuration: the recipes selected by `--profile` in the order they run, with `{args}`
+    /// substituted
+    fn to_markdown(&self, cli: Option<&Cli>) -> String {
+        let hashes = "#".repeat(self.heading_level as usize);
+        let mut s = String::new();
+        for target in self.targets.values().filter(|x| x.is_listed()) {
+            if target.is_phony() {
+                s.push_str(&format!("{hashes} {}", target.name));
+            } else {
+                s.push_str(&format!("{hashes} `{}`", target.name));
+            }
+            if !target.annotations.0.is_empty() {
+                s.push_str(&format!(" {}", target.annotations));
+            }
+            s.push_str("\n\n");
+
+            if !target.description.is_empty() {
+                s.push_str(&format!("{}\n\n", target.description));
+            }
+
+            if !target.dependencies.is_empty() {
+                // Write glob dependencies as their patterns instead of the matching paths
+                let mut written = HashSet::new();
+                for dependency in &target.dependencies {
+                    if let Some((pattern, _)) = target
+                        .patterns
+                        .iter()
+                        .find(|(_, paths)| paths.contains(dependency))
+                    {
+                        if written.insert(pattern) {
+                            s.push_str(&format!("* `{pattern}`\n"));
+                        }
+                        continue;
+                    }
+                    let is_file = target.glob.is_some()
+                        || self.targets.get(dependency).is_some_and(|x| !x.is_phony());
+                    if is_file && target.glob.is_none() && dependency.contains(['*', '?', '[']) {
+                        // Literal path that would otherwise be read as a glob
+                        s.push_str(&format!("* `\"{dependency}\"`\n"));
+                    } else if is_file {
+                        s.push_str(&format!("* `{dependency}`\n"));
+                    } else {
+                        s.push_str(&format!("* {dependency}\n"));
+                    }
+                }
+                s.push('\n');
+            }
+
+            if let Some(cli) = cli {
+                let args = target.args(cli);
+                let (profile, alt) = (cli.profile.as_deref(), cli.alt.as_deref());
+                for recipe in target
+                    .stage(Stage::Setup, profile, alt)
+                    .chain(target.stage(Stage::Main, profile, alt))
+                    .chain(target.stage(Stage::Teardown, profile, alt))
+                {
+                    s.push_str(&recipe.to_markdown(Some(&args)));
+                }
+            } else {
+                for recipe in &target.recipes {
+                    s.push_str(&recipe.to_markdown(None));
+                }
+            }
+        }
+        s
+    }
+
+    /// Resolve a target name given on the command line, falling back to a phony target whose slug
+    /// matches
+    fn resolve(&self, name: &str) -> String {
+        if !self.targets.contains_key(name) {
+            for target in self.targets.values() {
+                if target.is_phony() && slug(&target.name) == name {
+                    return target.name.clone();
+                }
+            }
+        }
+        name.to_string()
+    }
+
+    /// Return the order in which a target and its needed dependencies are processed
+    fn order(&self, target: &str, cli: &Cli, state: &mut State) -> Vec<String> {
+        let mut nodes = vec![];
+        add_node_and_deps(target, self, cli, &mut nodes, None, 0, state);
+        if nodes.len() > 1 {
+            DepGraph::new(&nodes).into_iter().collect()
+        } else {
+            nodes.iter().map(|x| x.id().clone()).collect()
+        }
+    }
+
+    /// Describe a target: kind, source, description, dependencies, recipes, and whether and why it
+    /// would run
+    fn describe(&self, name: &str, cli: &Cli) -> String {
+        let target = self.targets.get(name).unwrap();
+        let hashes = "#".repeat(self.heading_level as usize);
+        let quote = |x: &String| {
+            if self.targets.get(x).is_some_and(|x| !x.is_phony()) {
+                format!("`{x}`")
+            } else {
+                x.clone()
+            }
+        };
+
+        let mut s = format!("{hashes} {}\n\n", quote(&target.name));
+        s.push_str(&format!("* Kind: {}\n", target.kind()));
+        if !target.source.as_os_str().is_empty() {
+            s.push_str(&format!("* Source: `{}`\n", target.source.display()));
+        }
+        if !target.description.is_empty() {
+            s.push_str(&format!("* Description: {}\n", target.description));
+        }
+        if !target.annotations.0.is_empty() {
+            s.push_str(&format!("* Annotations: {}\n", target.annotations));
+        }
+
+        // Direct and transitive dependencies in depth-first order
+        let mut all = IndexSet::new();
+        let mut stack = target.dependencies.iter().rev().collect::<Vec<_>>();
+        while let Some(dependency) = stack.pop() {
+            if all.insert(dependency) {
+                if let Some(t) = self.targets.get(dependency) {
+                    stack.extend(t.dependencies.iter().rev());
+                }
+            }
+        }
+        let list = |x: Vec<&String>| x.into_iter().map(quote).collect::<Vec<_>>().join(", ");
+        if !target.dependencies.is_empty() {
+            let dependencies = list(target.dependencies.iter().collect());
+            s.push_str(&format!("* Dependencies: {dependencies}\n"));
+        }
+        if all.len() > target.dependencies.len() {
+            let dependencies = list(all.iter().copied().collect());
+            s.push_str(&format!("* All dependencies: {dependencies}\n"));
+        }
+
+        // Whether the target would run and why
+        let follow_symlinks = !cli.no_follow_symlinks;
+        let status = if target.glob.is_some() {
+            String::from("generates targets matching the pattern")
+        } else if let Some(ts) = target.dtg.as_ref() {
+            if !Path::new(&target.name).exists() {
+                if target.recipes.is_empty() {
+                    String::from("missing (no recipes to create it)")
+                } else {
+                    String::from("outdated: file does not exist")
+                }
+            } else if let Some(newer) = target.newer(ts, &self.targets) {
+                format!("outdated: `{newer}` is newer")
+            } else {
+                String::from("up to date")
+            }
+        } else if let Some(output) = target.annotations.get("output") {
+            let ts = mtime(output, follow_symlinks);
+            if !Path::new(output).exists() {
+                format!("outdated: `{output}` does not exist")
+            } else if let Some(newer) = target.dependencies.iter().find_map(|x| {
+                self.targets
+                    .get(x)
+                    .and_then(|x| x.newer(&ts, &self.targets))
+            }) {
+                format!("outdated: `{newer}` is newer than `{output}`")
+            } else {
+                String::from("up to date")
+            }
+        } else {
+            String::from("always runs")
+        };
+        s.push_str(&format!("* Status: {status}\n\n"));
+
+        for recipe in &target.recipes {
+            s.push_str(&recipe.to_markdown(None));
+        }
+        s
+    }
+
+    /// Hash a target's name, recipes, dependencies' fingerprints and `{inputs=...}` files' paths and
+    /// contents (in sorted order), and the contents of a file without recipes, so it only changes
+    /// if they do
+    fn fingerprint(&self, name: &str, memo: &mut HashMap<String, u64>) -> u64 {
+        if let Some(hash) = memo.get(name) {
+            return *hash;
+        }
+        // Guard against dependency cycles
+        memo.insert(name.to_string(), 0);
+
+        let mut hash = fnv1a(FNV_OFFSET, name.replace('\\', "/").as_bytes());
+        let target = self.targets.get(name);
+        let recipes = target.map(|x| x.recipes.as_slice()).unwrap_or_default();
+        for recipe in recipes {
+            hash = fnv1a(hash, recipe.to_markdown(None).as_bytes());
+        }
+        if let Some(target) = target {
+            let mut dependencies = target.dependencies.iter().collect::<Vec<_>>();
+            dependencies.sort();
+            for dependency in dependencies {
+                let dependency = self.fingerprint(dependency, memo);
+                hash = fnv1a(hash, &dependency.to_le_bytes());
+            }
+            let mut inputs = target.inputs().collect::<Vec<_>>();
+            inputs.sort();
+            for input in inputs {
+                hash = fnv1a(hash, input.replace('\\', "/").as_bytes());
+                if let Ok(contents) = std::fs::read(input) {
+                    hash = fnv1a(hash, &contents);
+                }
+            }
+        }
+        if recipes.is_empty() {
+            if let Ok(contents) = std::fs::read(name) {
+                hash = fnv1a(hash, &contents);
+            }
+        }
+
+        memo.insert(name.to_string(), hash);
+        hash
+    }
+
+    /// Render the dependency graph as DOT or Mermaid
+    ///
+    /// Nodes are numbered in order of first appearance so the output is deterministic.
+    fn graph(&self, format: GraphFormat) -> String {
+        let mut nodes: IndexSet<&str> = IndexSet::new();
+        let mut edges = vec![];
+        for target in self.targets.values() {
+            let (from, _) = nodes.insert_full(&target.name);
+            for dependency in &target.dependencies {
+                edges.push((from, nodes.insert_full(dependency).0));
+            }
+        }
+        let mut s = String::new();
+        match format {
+            GraphFormat::Dot => {
+                s.push_str("digraph {\n");
+                for (i, name) in nodes.iter().enumerate() {
+                    s.push_str(&format!("    n{i} [label={name:?}];\n"));
+                }
+                for (from, to) in &edges {
+                    s.push_str(&format!("    n{from} -> n{to};\n"));
+                }
+                s.push_str("}\n");
+            }
+            GraphFormat::Mermaid => {
+                s.push_str("graph TD\n");
+                for (i, name) in nodes.iter().enumerate() {
+                    let name = name.replace('"', "#quot;");
+                    s.push_str(&format!("    n{i}[\"{name}\"]\n"));
+                }
+                for (from, to) in &edges {
+                    s.push_str(&format!("    n{from} --> n{to}\n"));
+                }
+            }
+        }
+        s
+    }
+
+    /// Pick a phony target via `fzf` or a numbered menu
+    fn pick(&self) -> Result<String> {
+        if !std::io::stdin().is_terminal() {
+            error!(
+                10,
+                "ERROR: `--pick` requires a terminal; specify a target instead!"
+            );
+        }
+
+        let names = self
+            .targets
+            .values()
+            .filter(|x| x.is_phony() && !x.is_special())
+            .map(|x| x.name.clone())
+            .collect::<Vec<_>>();
+
+        if program_exists("fzf") {
+            let mut child = std::process::Command::new("fzf")
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()?;
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(names.join("\n").as_bytes())?;
+            let output = child.wait_with_output()?;
+            let name = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            if !output.status.success() || name.is_empty() {
+                error!(10, "ERROR: No target picked!");
+            }
+            return Ok(name);
+        }
+
+        for (i, name) in names.iter().enumerate() {
+            println!("{}. {name}", i + 1);
+        }
+        print!("\nTarget: ");
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)?;
+        let line = line.trim();
+        match line.parse::<usize>() {
+            Ok(i) if (1..=names.len()).contains(&i) => Ok(names[i - 1].clone()),
+            _ if names.iter().any(|x| x == line) => Ok(line.to_string()),
+            _ => {
+                error!(5, "ERROR: Invalid target: `{line}`!");
+            }
+        }
+    }
+
+    fn process(&mut self, cli: &Cli) -> Result<()> {
+        if cli.verbose >= 3 {
+            print_fence();
+            cprint!(*DEBUG, "\n{self:#?}\n");
+            print_end_fence();
+        }
+
+        // Separate named parameters (`NAME=VALUE`) from the targets
+        let (params, cli_targets): (Vec<_>, Vec<_>) =
+            cli.targets.iter().partition(|x| parse_param(x).is_some());
+
+        // Warnings found while loading the configuration (`--check` reports them itself)
+        let mut state = State::default();
+        if !cli.check {
+            for warning in &self.warnings {
+                state.warning(cli, warning.clone());
+            }
+        }
+
+        // Resolve target slugs (`build-all` for `# Build All`)
+        let cli_targets = cli_targets
+            .into_iter()
+            .map(|x| self.resolve(x))
+            .collect::<Vec<_>>();
+
+        // Check the configuration (`--check`)
+        if cli.check {
+            let errors = self.check(cli, None).report(cli.strict);
+            if errors > 0 {
+                error!(13, "ERROR: Configuration check found {errors} problem(s)!");
+            }
+            return Ok(());
+        }
+
+        // Print a target's direct dependencies (`--print-deps`)
+        if let Some(target) = &cli.print_deps {
+            let target = self.resolve(target);
+            let Some(t) = self.targets.get(&target) else {
+                error!(5, "ERROR: Invalid target: `{target}`!");
+            };
+            for dependency in &t.dependencies {
+                println!("{dependency}");
+            }
+            return Ok(());
+        }
+
+        // Print the processing order (`--show-order`)
+        if let Some(target) = &cli.show_order {
+            let target = self.resolve(target);
+            if !self.targets.contains_key(&target) {
+                error!(5, "ERROR: Invalid target: `{target}`!");
+            }
+            let order = self.order(&target, cli, &mut State::default());
+            for (i, name) in order.iter().enumerate() {
+                let kind = self.targets.get(name).map_or("missing", |x| x.kind());
+                println!("{}. {name} ({kind})", i + 1);
+            }
+            return Ok(());
+        }
+
+        // Print a report on a target (`--describe`)
+        if let Some(target) = &cli.describe {
+            let target = self.resolve(target);
+            if !self.targets.contains_key(&target) {
+                error!(5, "ERROR: Invalid target: `{target}`!");
+            }
+            print_markdown(&self.describe(&target, cli));
+            return Ok(());
+        }
+
+        // Print a target's fingerprint (`--fingerprint`)
+        if let Some(target) = &cli.fingerprint {
+            let target = self.resolve(target);
+            if !self.targets.contains_key(&target) {
+                error!(5, "ERROR: Invalid target: `{target}`!");
+            }
+            println!("{:016x}", self.fingerprint(&target, &mut HashMap::new()));
+            return Ok(());
+        }
+
+        // Print the dependency graph (`--graph`)
+        if let Some(format) = cli.graph {
+            print!("{}", self.graph(format));
+            return Ok(());
+        }
+
+        // Print targets and descriptions for shell completion (`--complete-targets`)
+        if cli.complete_targets {
+            for target in self.targets.values() {
+                if target.is_listed() && !target.is_special() {
+                    println!("{}\t{}", target.name, target.description);
+                }
+            }
+            return Ok(());
+        }
+
+        // Print the configuration as normalized markdown (`--format-config`)
+        if cli.format_config {
+            print_markdown(&self.to_markdown(None));
+            return Ok(());
+        }
+
+        // Print the effective configuration (`--effective-config`)
+        if cli.effective_config {
+            print_markdown(&self.to_markdown(Some(cli)));
+            return Ok(());
+        }
+
+        // List targets (`-l`)
+        if cli.list_targets {
+            if cli_targets.is_empty() {
+                let mut targets = self.targets.values().collect::<Vec<_>>();
+                if cli.sort {
+                    targets.sort_by(|a, b| a.name.cmp(&b.name));
+                }
+                for target in targets {
+                    if target.is_listed() && !target.is_special() {
+                        print_list_target(target, 0, cli.source);
+                    }
+                }
+            } else {
+                // Expand glob patterns to the matching target names (`-l 'build-*'`)
+                let mut expanded = vec![];
+                let mut invalid = vec![];
+                for target in cli_targets {
+                    if self.targets.contains_key(&target) {
+                        expanded.push(target);
+                        continue;
+                    }
+                    let matched = Glob::new(&target)
+                        .map(|x| {
+                            let matcher = x.compile_matcher();
+                            self.targets
+                                .keys()
+                                .filter(|x| matcher.is_match(x.as_str()))
+                                .cloned()
+                                .collect::<Vec<_>>()
+                        })
+                        .unwrap_or_default();
+                    if matched.is_empty() {
+                        invalid.push(format!("`{target}`"));
+                    } else {
+                        expanded.extend(matched);
+                    }
+                }
+
+                // Report all invalid targets
+                if !invalid.is_empty() {
+                    error!(5, "ERROR: Invalid target(s): {}!", invalid.join(", "));
+                }
+                let mut cli_targets = expanded;
+                if cli.sort {
+                    cli_targets.sort();
+                }
+                for target in &cli_targets {
+                    print_list_file_targets(target, &self.targets, 0, cli.sort, cli.source);
+                }
+            }
+            println!();
+            return Ok(());
+        }
+
+        // Which target(s) are we processing?
+        let targets = if cli.pick {
+            // Target picked interactively (`--pick`)
+            vec![self.pick()?]
+        } else if cli.all {
+            // All phony targets except special targets (`--all`)
+            self.targets
+                .values()
+                .filter(|x| x.is_phony() && !x.is_special())
+                .map(|x| x.name.clone())
+                .collect()
+        } else if !cli.tag.is_empty() {
+            // All targets with any of the tags (`--tag NAME`)
+            let targets = self
+                .targets
+                .values()
+                .filter(|x| x.tags().any(|tag| cli.tag.iter().any(|x| x == tag)))
+                .map(|x| x.name.clone())
+                .collect::<Vec<_>>();
+            if targets.is_empty() {
+                error!(5, "ERROR: No targets with tag(s): {}!", cli.tag.join(", "));
+            }
+            targets
+        } else if cli_targets.is_empty() {
+            // First target in `Makefile.md` except special targets (`.SHELLS`, `.ON_SUCCESS`, etc)
+            let Some(target) = self.targets.values().find(|x| !x.is_special()) else {
+                error!(5, "ERROR: No target to process!");
+            };
+            vec![target.name.clone()]
+        } else {
+            // Target(s) specified on the command line
+            cli_targets
+        };
+
+        // Process the target(s)
+        let mut outcomes = vec![];
+        for (name, value) in params.into_iter().filter_map(|x| parse_param(x)) {
+            state.exports.insert(name.to_string(), value.to_string());
+        }
+        let mut plans = vec![];
+        for target in &targets {
+            // Generate target from wildcard/glob target
+            if !self.targets.contains_key(target) {
+                let mut matched = false;
+                for (_, t) in &self.targets {
+                    if let Some(glob) = t.glob.as_ref() {
+                        if glob.is_match(target) {
+                            matched = true;
+                            let re = Regex::new(&format!("{}$", &t.name[2..])).expect("regex");
+                            let extension = &t.dependencies[0][2..];
+                            let dependency = re.replace(target, extension).to_string();
+                            let target_does_not_exist = !Path::new(target).exists();
+                            let apply = cli.force_processing
+                                || cli.no_deps
+                                || target_does_not_exist
+                                || outdated(&dependency, target, !cli.no_follow_symlinks)
+                                || cli.changed.contains(&dependency);
+                            if cli.explain {
+                                explain_wildcard(target, &t.name, Some(&dependency), apply);
+                            }
+                            if apply {
+                                let t = t.instantiate(target, &dependency);
+                                self.targets.insert(target.clone(), t);
+                            }
+                            break;
+                        } else if cli.explain {
+                            explain_wildcard(target, &t.name, None, false);
+                        }
+                    }
+                }
+                if cli.explain && !matched {
+                    ecprint!(*DEBUG, "No wildcard target matches `{target}`\n");
+                }
+            }
+
+            // Fail on an empty phony target (`--fail-on-missing-recipe`)
+            if cli.fail_on_missing_recipe {
+                if let Some(t) = self.targets.get(target) {
+                    if t.is_empty_phony() {
+                        state.problem(
+                            cli,
+                            7,
+                            format!("Target `{target}` has no dependencies or recipes"),
+                        );
+                    }
+                }
+            }
+
+            let order = self.order(target, cli, &mut state);
+
+            // Unresolved recipe variables (and warnings if `--strict`) of the targets to process
+            // are problems too in a dry run
+            if cli.dry_run {
+                for problem in self.check(cli, Some(&order)).errors(cli.strict) {
+                    state.problem(cli, 13, problem);
+                }
+            }
+
+            // Run the target N times regardless of whether it is outdated, and its dependencies
+            // once or also N times (`--repeat N`, `--repeat-deps`)
+            let mut jobs = order
+                .iter()
+                .map(|x| (x.clone(), cli.repeat.is_some() && x == target))
+                .collect::<Vec<_>>();
+            for _ in 1..cli.repeat.unwrap_or(1) {
+                if cli.repeat_deps {
+                    jobs.extend(order.iter().map(|x| (x.clone(), true)));
+                } else {
+                    jobs.push((target.clone(), true));
+                }
+            }
+            plans.push(jobs);
+        }
+
+        // Count only the targets that will run in the `[n/total]` progress prefix, across all
+        // requested targets; a `{once}` target that already ran is skipped
+        let mut once = state.once.clone();
+        let plans = plans
+            .into_iter()
+            .map(|jobs| {
+                jobs.into_iter()
+                    .map(|(name, forced)| {
+                        let runs = if forced {
+                            self.targets
+                                .get(&name)
+                                .is_some_and(|x| !x.recipes.is_empty())
+                        } else {
+                            will_run(&name, &self.targets, cli)
+                        };
+                        let runs = runs
+                            && (!self.targets[&name].annotations.has("once")
+                                || once.insert(name.clone()));
+                        (name, forced, runs)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        state.progress = (0, plans.iter().flatten().filter(|x| x.2).count());
+
+        // Run the failure hook (`.ON_FAILURE`) if processing exits early via `error!`
+        if !cli.dry_run {
+            if let Some(hook) = self.targets.get(".ON_FAILURE") {
+                *FAILURE_HOOK.lock().unwrap() = Some((hook.clone(), cli.clone(), String::new()));
+            }
+        }
+
+        let run_start = Instant::now();
+        let mut blocked = HashSet::new();
+        'targets: for jobs in plans {
+            for (name, forced, runs) in jobs {
+                if runs {
+                    state.progress.0 += 1;
+                }
+                if let Some((_, _, target)) = FAILURE_HOOK.lock().unwrap().as_mut() {
+                    target.clone_from(&name);
+                }
+
+                // Skip a target that depends on a failed or skipped target (`-k`)
+                if self
+                    .targets
+                    .get(&name)
+                    .is_some_and(|x| x.dependencies.iter().any(|x| blocked.contains(x)))
+                {
+                    blocked.insert(name);
+                    continue;
+                }
+
+                let start = Instant::now();
+                let status = if forced {
+                    self.targets
+                        .get(&name)
+                        .filter(|x| !x.recipes.is_empty())
+                        .map(|x| x.process(cli, &mut state))
+                } else {
+                    process_target(&name, &self.targets, cli, &mut state)
+                };
+                if let Some(status) = status {
+                    // A failed repeated run does not stop the others (`--repeat N`)
+                    let failed = matches!(status, Status::Failed(_)) && !forced;
+                    if failed {
+                        blocked.insert(name.clone());
+                    }
+                    outcomes.push(Outcome {
+                        target: name,
+                        status,
+                        started: start.duration_since(run_start),
+                        duration: start.elapsed(),
+                    });
+                    if failed {
+                        // Keep running the targets that do not depend on the failed target (`-k`),
+                        // or stop at the Nth failure (`--keep-going-until N`)
+                        let failures = outcomes.iter().filter(|x| x.failure().is_some()).count();
+                        if cli.keep_going || cli.keep_going_until.is_some_and(|n| failures < n) {
+                            continue;
+                        }
+                        break 'targets;
+                    }
+                }
+            }
+        }
+
+        // Processing is complete, so the failure hook runs below if needed
+        FAILURE_HOOK.lock().unwrap().take();
+
+        // Nothing else to do after printing the plan (`--plan-only`)
+        if cli.plan_only {
+            return Ok(());
+        }
+
+        // No progress prefix for the hooks' headings
+        state.progress = (0, 0);
+
+        // Print the number of up to date targets (`-vv` without `--verbose-uptodate`)
+        if cli.verbose >= 2 && !cli.verbose_uptodate {
+            let n = outcomes
+                .iter()
+                .filter(|x| matches!(x.status, Status::UpToDate))
+                .count();
+            if n > 0 {
+                cprint!(*UP_TO_DATE, "*{n} target(s) up to date*\n\n");
+            }
+        }
+
+        // Print the number of passed, failed, and skipped runs of each target (`--repeat N`)
+        if cli.repeat.is_some() {
+            for target in &targets {
+                let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+                for x in outcomes.iter().filter(|x| &x.target == target) {
+                    match x.status {
+                        Status::Succeeded => passed += 1,
+                        Status::Failed(_) => failed += 1,
+                        Status::UpToDate | Status::Skipped => skipped += 1,
+                    }
+                }
+                let skipped = if skipped > 0 {
+                    format!(" ({skipped} skipped)")
+                } else {
+                    String::new()
+                };
+                cprint!(
+                    *UP_TO_DATE,
+                    "*Target `{target}` ran {} time(s): {passed} passed, {failed} failed\
+                    {skipped}*\n\n",
+                    passed + failed,
+                );
+            }
+        }
+
+        // Print the size and modification time of each (re)built file (`--list-produced`)
+        if cli.list_produced && !cli.dry_run {
+            let files = outcomes
+                .iter()
+                .filter(|x| matches!(x.status, Status::Succeeded))
+                .filter(|x| {
+                    // A target generated from a wildcard target is not in the configuration
+                    self.targets.get(&x.target).is_none_or(|x| x.dtg.is_some())
+                })
+                .map(|x| x.target.as_str())
+                .collect::<Vec<_>>();
+            print_produced(&files);
+        }
+
+        // Print the executed commands' exit codes and durations (`-vv`)
+        if cli.verbose >= 2 && !state.commands.is_empty() {
+            state.print_commands();
+        }
+
+        // Report all problems found in a dry run (`-n`) together and exit with the first one's code
+        if let Some((code, _)) = state.problems.first() {
+            for (_, problem) in &state.problems {
+                ecprint!(*ERROR, "ERROR: {problem}!\n");
+            }
+            error!(
+                *code,
+                "ERROR: Dry run found {} problem(s)!",
+                state.problems.len()
+            );
+        }
+
+        // Write the report (`--report`)
+        if let Some(path) = &cli.report {
+            write_report(path, &outcomes)?;
+        }
+
+        // Write the trace (`--trace-file`)
+        if let Some(path) = &cli.trace_file {
+            write_trace(path, &outcomes)?;
+        }
+
+        // Run the failure hook (`.ON_FAILURE`) and halt with the failed command's exit code, or run
+        // the success hook (`.ON_SUCCESS`)
+        let failures = outcomes
+            .iter()
+            .filter_map(|x| x.failure().map(|failure| (&x.target, failure)))
+            .collect::<Vec<_>>();
+        if cli.keep_going || cli.keep_going_until.is_some() {
+            for (target, failure) in &failures {
+                ecprint!(
+                    *ERROR,
+                    "ERROR: Target `{target}` failed with exit code {}!\n",
+                    failure.code
+                );
+            }
+        }
+        let failed = failures.first();
+        if let Some((target, failure)) = failed {
+            if let Some(hook) = self.targets.get(".ON_FAILURE") {
+                std::env::set_var("MKRS_FAILED_TARGET", target);
+                std::env::set_var("MKRS_FAILED_COMMAND", &failure.command);
+                std::env::set_var("MKRS_EXIT_CODE", failure.code.to_string());
+                // A failed hook does not change the exit code
+                let _ = hook.run(cli, &mut state);
+            }
+            std::process::exit(failure.code);
+        } else if let Some(hook) = self.targets.get(".ON_SUCCESS") {
+            if let Err(failure) = hook.run(cli, &mut state) {
+                std::process::exit(failure.code);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+
+/// When a recipe runs relative to a target's main recipes
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stage {
+    /// Before the main recipes (`{setup}`)
+    Setup,
+
+    /// Main recipes
+    Main,
+
+    /// After the main recipes, even if they fail (`{teardown}`)
+    Teardown,
+}
+
+#[derive(Clone, Debug)]
 struct Recipe {
     shell: Option<String>,
+    literal: bool,
+    login: bool,
+    stage: Stage,
+    profile: Option<String>,
+    alt: Option<String>,
+    stdout: Option<String>,
+    stderr: Option<String>,
+    condition: Option<String>,
     commands: Vec<String>,
 }
 
 impl Recipe {
-    fn new(shell: Option<String>, commands: Vec<String>) -> Recipe {
-        Recipe { shell, commands }
+    fn new(shell: Option<String>, annotations: &Annotations, commands: Vec<String>) -> Recipe {
+        let stage = if annotations.has("setup") {
+            Stage::Setup
+        } else if annotations.has("teardown") {
+            Stage::Teardown
+        } else {
+            Stage::Main
+        };
+        Recipe {
+            shell,
+            literal: annotations.has("literal"),
+            login: annotations.has("login"),
+            stage,
+            profile: annotations.get("profile").map(String::from),
+            alt: annotations.get("alt").map(String::from),
+            stdout: annotations.get("stdout").map(String::from),
+            stderr: annotations.get("stderr").map(String::from),
+            condition: annotations.get("if-cmd").map(String::from),
+            commands,
+        }
+    }
+
+    fn run(&self, target: &str, args: &str, cli: &Cli, state: &mut State) -> Result<(), Failure> {
+        // Skip the recipe unless its guard command succeeds (`{if-cmd="COMMAND"}`)
+        if let Some(condition) = &self.condition {
+            let condition = self.substitute(condition, args, state);
+            let quiet = cli.quiet || cli.quiet_unless_fail;
+            if !cli.dry_run
+                && capture(Command::new(&condition), false, quiet, cli.nice).code != Some(0)
+            {
+                return Ok(());
+            }
+        }
+
+        // Create or truncate the files for redirected output (`{stdout=PATH}`, `{stderr=PATH}`)
+        if !cli.dry_run {
+            for path in [&self.stdout, &self.stderr].into_iter().flatten() {
+                let path = Path::new(path);
+                let created = path
+                    .parent()
+                    .filter(|x| !x.as_os_str().is_empty())
+                    .map_or(Ok(()), std::fs::create_dir_all)
+                    .and_then(|()| std::fs::File::create(path).map(|_| ()));
+                if created.is_err() {
+                    error!(14, "ERROR: Could not create `{}`!", path.display());
+                }
+            }
+        }
+
+        // Buffer output to show only if the target fails (`--quiet-unless-fail`) or to write to
+        // the target's log file (`--log-dir`)
+        let buffer = cli.quiet_unless_fail || cli.log_dir.is_some();
+        let quiet = cli.quiet || cli.quiet_unless_fail;
+
+        // Copy the output not redirected to a file to the terminal as it is produced while
+        // buffering it for the log file (`--log-dir`)
+        let tee = (cli.log_dir.is_some() && !cli.quiet_unless_fail && !cli.dry_run)
+            .then_some((self.stdout.is_none(), self.stderr.is_none()));
+
+        // Print each would-be command line with a prefix instead of the usual dry run output
+        // (`--dry-run-prefix`)
+        let prefix = cli
+            .dry_run_prefix
+            .as_deref()
+            .filter(|_| cli.dry_run && !quiet);
+        let quiet = quiet || prefix.is_some();
+
+        // Run a recipe without a custom command as a script in a login shell (`{login}`,
+        // `--login-shell`)
+        let login = self.shell.is_none() && (self.login || cli.login_shell);
+
+        if self.shell.is_some() || cli.script_mode || login {
+            let script = self
+                .commands
+                .iter()
+                .map(|x| self.substitute(x, args, state))
+                .collect::<Vec<_>>()
+                .join("\n");
+            if let Some(prefix) = prefix {
+                print_prefixed(prefix, &script);
+                println!();
+            }
+            let start = Instant::now();
+            let result = run_script(
+                &script,
+                cli.dry_run,
+                quiet,
+                &script_shell(self.shell.as_deref(), cli.verbose, login),
+                cli.nice,
+                self.command("", buffer),
+                tee,
+            );
+            self.write_output(&script, &result, buffer, state);
+            state.record(target, &script, &result, start.elapsed());
+            check_result(&result, &script, cli.dry_run)
+        } else {
+            for command in &self.commands {
+                // Substitute each command just before running it so it can use values captured by
+                // previous commands
+                let command = self.substitute(command, args, state);
+                let start = Instant::now();
+                if let Some((name, command)) = parse_capture(&command) {
+                    // Capture the command's output into the `{NAME}` variable
+                    if let Some(prefix) = prefix {
+                        print_prefixed(prefix, command);
+                    }
+                    let result = match tee {
+                        Some((_, stderr)) => run_tee(
+                            &shell(cli.dry_run, quiet, cli.nice),
+                            &self.command(command, buffer),
+                            (false, stderr),
+                        ),
+                        None => {
+                            capture(self.command(command, buffer), cli.dry_run, quiet, cli.nice)
+                        }
+                    };
+                    self.write_output(command, &result, buffer, state);
+                    state.record(target, command, &result, start.elapsed());
+                    check_result(&result, command, cli.dry_run)?;
+                    if let Pipe::String(Some(stdout)) = &result.stdout {
+                        state
+                            .exports
+                            .insert(name.to_string(), stdout.trim_end().to_string());
+                    }
+                } else {
+                    if let Some(prefix) = prefix {
+                        print_prefixed(prefix, &command);
+                    }
+                    let result = match tee {
+                        Some(echo) => run_tee(
+                            &shell(cli.dry_run, quiet, cli.nice),
+                            &self.command(&command, buffer),
+                            echo,
+                        ),
+                        None => run(self.command(&command, buffer), cli.dry_run, quiet, cli.nice),
+                    };
+                    self.write_output(&command, &result, buffer, state);
+                    state.record(target, &command, &result, start.elapsed());
+                    check_result(&result, &command, cli.dry_run)?;
+                }
+            }
+            if prefix.is_some() {
+                println!();
+            }
+            Ok(())
+        }
+    }
+
+    /// Build a command that captures any output redirected to a file (`{stdout=PATH}`,
+    /// `{stderr=PATH}`) or all output if buffering
+    fn command(&self, command: &str, buffer: bool) -> Command {
+        let command = Command::new(command);
+        Command {
+            stdout: if buffer || self.stdout.is_some() {
+                Pipe::String(None)
+            } else {
+                command.stdout.clone()
+            },
+            stderr: if buffer || self.stderr.is_some() {
+                Pipe::String(None)
+            } else {
+                command.stderr.clone()
+            },
+            ..command
+        }
+    }
+
+    /// Append a command's redirected output to its file, and the rest of its output to the
+    /// target's buffer if buffering
+    fn write_output(&self, command: &str, result: &Command, buffer: bool, state: &mut State) {
+        if buffer {
+            state.output.push_str(&format!("$ {command}\n"));
+        }
+        for (path, pipe) in [
+            (&self.stdout, &result.stdout),
+            (&self.stderr, &result.stderr),
+        ] {
+            let Pipe::String(Some(s)) = pipe else {
+                continue;
+            };
+            if let Some(path) = path {
+                let written = std::fs::OpenOptions::new()
+                    .append(true)
+                    .open(path)
+                    .and_then(|mut f| f.write_all(s.as_bytes()));
+                if written.is_err() {
+                    error!(14, "ERROR: Could not write `{path}`!");
+                }
+            } else if buffer {
+                state.output.push_str(s);
+            }
+        }
+    }
+
+    /// Pipe a shell recipe's commands through the linter and exit if it reports errors; variables
+    /// are substituted and capture lines (`{capture NAME}= COMMAND`) are linted as the shell
+    /// assignments `NAME=$(COMMAND)` they resemble
+    fn lint(&self, target: &str, linter: &str, args: &str, state: &State) {
+        if self.shell.as_ref().is_some_and(|x| !is_shell(x)) {
+            // Custom program (python, etc)
+            return;
+        }
+
+        let mut captures = vec![];
+        let mut script = self
+            .commands
+            .iter()
+            .map(|x| {
+                let x = self.substitute(x, args, state);
+                match parse_capture(&x) {
+                    Some((name, command)) => {
+                        captures.push(name.to_string());
+                        format!("{name}=$({command})")
+                    }
+                    None => x,
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        for name in captures {
+            script = script.replace(&format!("{{{name}}}"), &format!("\"${{{name}}}\""));
+        }
+
+        let result = Shell {
+            print: false,
+            ..base_shell()
+        }
+        .core(&Command {
+            command: linter.to_string(),
+            stdin: Pipe::String(Some(script)),
+            ..Default::default()
+        });
+
+        if result.code != Some(0) {
+            error!(8, "ERROR: Recipe for target `{target}` failed linting!");
+        }
+    }
+
+    /// Return the programs run by the recipe that are not in the allowed list: the first word of
+    /// each line of a shell recipe or the custom program (python, etc), and of the guard command
+    /// (`{if-cmd="COMMAND"}`)
+    fn disallowed(&self, allowed: &[String]) -> Vec<String> {
+        let programs = match &self.shell {
+            Some(shell) if !is_shell(shell) => vec![shell.as_str()],
+            _ => self
+                .commands
+                .iter()
+                .flat_map(|x| x.lines())
+                .map(|x| parse_capture(x).map_or(x, |(_, command)| command).trim())
+                .filter(|x| !x.is_empty() && !x.starts_with('#'))
+                .collect(),
+        };
+        programs
+            .into_iter()
+            .chain(self.condition.as_deref())
+            .filter_map(|x| x.split_whitespace().next())
+            .filter(|x| !allowed.iter().any(|y| y == x))
+            .map(String::from)
+            .collect()
+    }
+
+    /// Substitute `{args}` and values exported or captured by previous targets/commands, unless
+    /// literal
+    fn substitute(&self, command: &str, args: &str, state: &State) -> String {
+        if self.literal {
+            command.to_string()
+        } else {
+            state.substitute(command).replace("{args}", args)
+        }
+    }
+
+    /// Return true if another recipe can be appended to this one to run in the same script: both
+    /// run in the default shell with the same annotations
+    fn can_combine(&self, other: &Recipe) -> bool {
+        self.shell.is_none()
+            && other.shell.is_none()
+            && self.literal == other.literal
+            && self.login == other.login
+            && self.stdout == other.stdout
+            && self.stderr == other.stderr
+            && self.condition == other.condition
+    }
+
+    /// Return true if the recipe has no commands (only blank lines or comments)
+    fn is_noop(&self) -> bool {
+        self.commands.iter().flat_map(|x| x.lines()).all(|x| {
+            let x = x.trim();
+            x.is_empty() || x.starts_with('#')
+        })
+    }
+
+    /// Return the commands of a plain recipe, other than the last, that only change the state of
+    /// their own shell (`cd`, `export`, etc), which is lost since each command runs independently
+    /// (unless in script mode)
+    fn lost_state(&self) -> Vec<&str> {
+        if self.shell.is_some() || self.login {
+            return vec![];
+        }
+        let Some((_, commands)) = self.commands.split_last() else {
+            return vec![];
+        };
+        commands
+            .iter()
+            .map(|x| x.trim())
+            .filter(|x| !x.contains("&&") && !x.contains("||") && !x.contains(';'))
+            .filter(|x| {
+                let mut words = x.split_whitespace();
+                let first = words.next().unwrap_or_default();
+                matches!(
+                    first,
+                    "cd" | "pushd" | "popd" | "export" | "unset" | "set" | "source" | "." | "alias"
+                ) || (words.next().is_none()
+                    && first.split_once('=').is_some_and(|(name, _)| {
+                        !name.is_empty()
+                            && !name.starts_with(|c: char| c.is_ascii_digit())
+                            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                    }))
+            })
+            .collect()
+    }
+
+    /// Return `{...}` variables that will not be substituted, ignoring `${VAR}`, `{args}`,
+    /// `{A.key}` values exported by target `A`, and captured values
+    fn unresolved(
+        &self,
+        targets: &IndexMap<String, Target>,
+        captures: &HashSet<&str>,
+    ) -> Vec<String> {
+        // Braces may mean something else to a custom program (python, etc)
+        if self.literal || self.shell.as_ref().is_some_and(|x| !is_shell(x)) {
+            return vec![];
+        }
+        let re = Regex::new(r"(^|[^$])(\{([A-Za-z0-9_][A-Za-z0-9_.-]*)\})").expect("regex");
+        self.commands
+            .iter()
+            .map(|x| without_single_quoted(x))
+            .flat_map(|command| {
+                re.captures_iter(&command)
+                    .filter(|x| {
+                        let name = &x[3];
+                        name != "args"
+                            && !captures.contains(name)
+                            && !name.contains("..")
+                            && !name
+                                .rsplit_once('.')
+                                .is_some_and(|(target, _)| targets.contains_key(target))
+                    })
+                    .map(|x| x[2].to_string())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Serialize the recipe as a fenced code block
+    fn to_markdown(&self, args: Option<&str>) -> String {
+        let mut annotations = vec![];
+        if self.literal {
+            annotations.push((String::from("literal"), None));
+        }
+        if self.login {
+            annotations.push((String::from("login"), None));
+        }
+        match self.stage {
+            Stage::Setup => annotations.push((String::from("setup"), None)),
+            Stage::Teardown => annotations.push((String::from("teardown"), None)),
+            Stage::Main => {}
+        }
+        if let Some(profile) = &self.profile {
+            annotations.push((String::from("profile"), Some(profile.clone())));
+        }
+        if let Some(alt) = &self.alt {
+            annotations.push((String::from("alt"), Some(alt.clone())));
+        }
+        if let Some(path) = &self.stdout {
+            annotations.push((String::from("stdout"), Some(path.clone())));
+        }
+        if let Some(path) = &self.stderr {
+            annotations.push((String::from("stderr"), Some(path.clone())));
+        }
+        if let Some(condition) = &self.condition {
+            annotations.push((String::from("if-cmd"), Some(condition.clone())));
+        }
+        let annotations = Annotations(annotations);
+        let info = match (&self.shell, annotations.0.is_empty()) {
+            (Some(shell), true) => shell.clone(),
+            (Some(shell), false) => format!("{shell} {annotations}"),
+            (None, true) => String::new(),
+            (None, false) => annotations.to_string(),
+        };
+        let commands = if let Some(args) = args {
+            let state = State::default();
+            self.commands
+                .iter()
+                .map(|x| self.substitute(x, args, &state))
+                .collect::<Vec<_>>()
+        } else {
+            self.commands.clone()
+        };
+        format!("```{info}\n{}\n```\n\n", commands.join("\n"))
+    }
+
+    fn fix(&self, target: &str, dependency: &str) -> Recipe {
+        Recipe {
+            shell: self.shell.clone(),
+            literal: self.literal,
+            login: self.login,
+            stage: self.stage,
+            profile: self.profile.clone(),
+            alt: self.alt.clone(),
+            stdout: self.stdout.clone(),
+            stderr: self.stderr.clone(),
+            condition: self.condition.clone(),
+            commands: if self.literal {
+                self.commands.clone()
+            } else {
+                self.commands
+                    .iter()
+                    .map(|x| x.replace("{0}", dependency).replace("{target}", target))
+                    .collect()
+            },
+        }
+    }
+}
+
+//--------------------------------------------------------------------------------------------------
+
+/// Annotation keys understood in target headings and recipe code block info strings, also reported
+/// as features by `--version --json`
+const ANNOTATIONS: &[&str] = &[
+    "alt",
+    "args-default",
+    "confirm",
+    "deprecated",
+    "deps-command",
+    "deps-glob",
+    "expect-fail",
+    "extend",
+    "if-cmd",
+    "inputs",
+    "literal",
+    "login",
+    "once",
+    "output",
+    "override",
+    "params",
+    "path+",
+    "profile",
+    "setup",
+    "shell",
+    "stderr",
+    "stdout",
+    "tags",
+    "teardown",
+];
+
+/// `{key}` or `{key=value}` annotations in a target heading or recipe code block info string
+#[derive(Clone, Debug, Default)]
+struct Annotations(Vec<(String, Option<String>)>);
+
+impl Annotations {
+    /// Split the annotations from a string and return the remaining text
+    ///
+    /// Annotations in a pair of braces are separated by whitespace and/or commas; values with
+    /// whitespace must be quoted: `{key="some value", flag}`.
+    fn parse(s: &str) -> (String, Annotations) {
+        let mut text = String::new();
+        let mut annotations = vec![];
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            let mut quoted = false;
+            let Some(end) = rest[start..].find(|c| {
+                if is_quote(c) {
+                    quoted = !quoted;
+                }
+                c == '}' && !quoted
+            }) else {
+                break;
+            };
+            text.push_str(&rest[..start]);
+            for token in annotation_tokens(&rest[start + 1..start + end]) {
+                annotations.push(match token.split_once('=') {
+                    Some((key, value)) => (key.to_string(), Some(value.to_string())),
+                    None => (token, None),
+                });
+            }
+            rest = &rest[start + end + 1..];
+        }
+        text.push_str(rest);
+        (text.trim().to_string(), Annotations(annotations))
+    }
+
+    fn has(&self, key: &str) -> bool {
+        debug_assert!(ANNOTATIONS.contains(&key), "unlisted annotation `{key}`");
+        self.0.iter().any(|(k, _)| k == key)
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        debug_assert!(ANNOTATIONS.contains(&key), "unlisted annotation `{key}`");
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .and_then(|(_, v)| v.as_deref())
+    }
+
+    /// Return the values of a repeatable annotation (`{path+=DIR}`)
+    fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        debug_assert!(ANNOTATIONS.contains(&key), "unlisted annotation `{key}`");
+        self.0
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .filter_map(|(_, v)| v.as_deref())
+    }
+}
+
+impl std::fmt::Display for Annotations {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let annotations = self
+            .0
+            .iter()
+            .map(|(key, value)| match value {
+                Some(value) if value.is_empty() || value.contains(char::is_whitespace) => {
+                    format!("{key}=\"{value}\"")
+                }
+                Some(value) => format!("{key}={value}"),
+                None => key.clone(),
+            })
+            .collect::<Vec<_>>();
+        write!(f, "{{{}}}", annotations.join(" "))
     }
+}
 
-    fn run(&self, dry_run: bool, verbose: u8, quiet: bool, script_mode: bool) {
-        if let Some(shell) = &self.shell {
-            run_script(
-                &self.commands.join("\n"),
-                dry_run,
-                verbose,
-                quiet,
-                Some(shell.clone()),
-            );
-        } else if script_mode {
-            run_script(&self.commands.join("\n"), dry_run, verbose, quiet, None);
-        } else {
-            for command in &self.commands {
-                run(command, dry_run, quiet);
+/// Split the contents of an annotation block into tokens
+fn annotation_tokens(s: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut token = String::new();
+    let mut quoted = false;
+    for c in s.chars() {
+        if is_quote(c) {
+            quoted = !quoted;
+        } else if !quoted && (c.is_whitespace() || (c == ',' && !token.contains('='))) {
+            // Whitespace or a comma after a `{key}` annotation separates annotations; a comma
+            // within a value is kept (`{key=a,b}`), and a trailing comma is removed below
+            if !token.is_empty() {
+                tokens.push(std::mem::take(&mut token));
             }
+        } else {
+            token.push(c);
         }
     }
-
-    fn fix(&self, target: &str, dependency: &str) -> Recipe {
-        Recipe {
-            shell: self.shell.clone(),
-            commands: self
-                .commands
-                .iter()
-                .map(|x| x.replace("{0}", dependency).replace("{target}", target))
-                .collect(),
-        }
+    if !token.is_empty() {
+        tokens.push(token);
     }
+    tokens
+        .into_iter()
+        .map(|x| x.trim_end_matches(',').to_string())
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+/// Return true if a straight or curly double quote
+fn is_quote(c: char) -> bool {
+    matches!(c, '"' | '“' | '”')
 }
 
 //--------------------------------------------------------------------------------------------------
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct Target {
     name: String,
     glob: Option<GlobMatcher>,
     dtg: Option<std::time::SystemTime>,
     dependencies: Vec<String>,
+    patterns: Vec<(String, Vec<String>)>,
     recipes: Vec<Recipe>,
+    annotations: Annotations,
+    description: String,
+    source: PathBuf,
 }
 
 impl Target {
@@ -762,9 +3674,110 @@ impl Target {
         Target {
             name: name.to_owned(),
             glob,
-            dtg: is_file.then(|| mtime(name)),
+            dtg: is_file.then(|| mtime(name, true)),
             dependencies: dependencies.to_owned(),
+            patterns: vec![],
             recipes,
+            annotations: Annotations::default(),
+            description: String::new(),
+            source: PathBuf::new(),
+        }
+    }
+
+    /// Run the target unless it requires confirmation (`{confirm}`) and the user declines; a target
+    /// expected to fail (`{expect-fail}`) succeeds only if it fails
+    fn process(&self, cli: &Cli, state: &mut State) -> Status {
+        // Run a target at most once per invocation (`{once}`)
+        if self.annotations.has("once") && !state.once.insert(self.name.clone()) {
+            return Status::Skipped;
+        }
+
+        // Print the name of the target instead of running it (`--plan-only`); a phony target
+        // without recipes only groups its dependencies and is not counted in the progress total
+        if cli.plan_only {
+            if !self.recipes.is_empty() {
+                println!("{}", self.name);
+            }
+            return Status::Succeeded;
+        }
+
+        if self.annotations.has("confirm") && !cli.yes && !cli.dry_run && !confirm(&self.name) {
+            self.print_heading(cli, state);
+            print_skipped();
+            return Status::Skipped;
+        }
+
+        // Show the commands about to run and wait for the user (`--step`)
+        if cli.step && !self.recipes.is_empty() {
+            let commands = [Stage::Setup, Stage::Main, Stage::Teardown]
+                .into_iter()
+                .flat_map(|x| self.stage(x, cli.profile.as_deref(), cli.alt.as_deref()))
+                .flat_map(|x| &x.commands)
+                .collect::<Vec<_>>();
+            if !step(&self.name, &commands) {
+                self.print_heading(cli, state);
+                print_skipped();
+                return Status::Skipped;
+            }
+        }
+
+        // Warn about a deprecated target (`{deprecated}` or `{deprecated="MESSAGE"}`)
+        if self.annotations.has("deprecated") {
+            let message = self
+                .annotations
+                .get("deprecated")
+                .map(|x| format!(": {x}"))
+                .unwrap_or_default();
+            state.warning(
+                cli,
+                format!("Target `{}` is deprecated{message}", self.name),
+            );
+        }
+
+        let result = self.run(cli, state);
+
+        // Invert the result of a target that is expected to fail (`{expect-fail}`)
+        if self.annotations.has("expect-fail") && !cli.dry_run {
+            return match result {
+                Ok(()) => Status::Failed(Failure {
+                    command: self
+                        .stage(Stage::Main, cli.profile.as_deref(), cli.alt.as_deref())
+                        .flat_map(|x| &x.commands)
+                        .last()
+                        .cloned()
+                        .unwrap_or_default(),
+                    code: 1,
+                }),
+                Err(_) => Status::Succeeded,
+            };
+        }
+
+        // Check that a file target's recipes updated it (`--verify-mtime`)
+        if cli.verify_mtime && !cli.dry_run && self.dtg.is_some() && result.is_ok() {
+            self.verify_mtime(cli, state);
+        }
+
+        result.into()
+    }
+
+    /// Warn if a file target is still older than a dependency after its recipes ran, since it
+    /// will be outdated again next time
+    fn verify_mtime(&self, cli: &Cli, state: &mut State) {
+        let follow_symlinks = !cli.no_follow_symlinks;
+        let ts = mtime(&self.name, follow_symlinks);
+        if let Some(dependency) = self
+            .dependencies
+            .iter()
+            .find(|x| Path::new(x).exists() && mtime(x, follow_symlinks) > ts)
+        {
+            state.warning(
+                cli,
+                format!(
+                    "File target `{}` is older than dependency `{dependency}` after running its \
+                    recipes, so it will be outdated again; does a recipe update it",
+                    self.name,
+                ),
+            );
         }
     }
 
@@ -773,52 +3786,976 @@ impl Target {
         reference: &std::time::SystemTime,
         targets: &IndexMap<String, Target>,
     ) -> bool {
-        if let Some(ts) = self.dtg.as_ref() {
-            if ts > reference {
-                true
-            } else {
-                self.dependencies
-                    .iter()
-                    .any(|x| targets.get(x).unwrap().outdated(reference, targets))
-            }
+        self.newer(reference, targets).is_some()
+    }
+
+    /// Return the first file (this target or a dependency) newer than the reference
+    fn newer<'a>(
+        &'a self,
+        reference: &std::time::SystemTime,
+        targets: &'a IndexMap<String, Target>,
+    ) -> Option<&'a str> {
+        self.newer_memo(reference, targets, &mut HashSet::new())
+    }
+
+    /// Find a newer file, skipping targets already found not to be outdated (shared dependencies
+    /// in a diamond-shaped graph)
+    fn newer_memo<'a>(
+        &'a self,
+        reference: &std::time::SystemTime,
+        targets: &'a IndexMap<String, Target>,
+        checked: &mut HashSet<&'a str>,
+    ) -> Option<&'a str> {
+        if !checked.insert(&self.name) {
+            return None;
+        }
+        if let Some(input) = self.inputs().find(|x| mtime(x, true) > *reference) {
+            return Some(input);
+        }
+        let ts = self.dtg.as_ref()?;
+        if ts > reference {
+            Some(&self.name)
+        } else {
+            self.dependencies.iter().find_map(|x| {
+                targets
+                    .get(x)
+                    .unwrap()
+                    .newer_memo(reference, targets, checked)
+            })
+        }
+    }
+
+    /// Return true if listed by `-l` (a phony target or a file target with dependencies or
+    /// recipes)
+    fn is_listed(&self) -> bool {
+        self.dtg.is_none() || !self.dependencies.is_empty() || !self.recipes.is_empty()
+    }
+
+    /// Generate a file target from a wildcard target; it inherits the wildcard target's
+    /// annotations (`{shell=...}`, etc) and recipes with their custom commands
+    fn instantiate(&self, target: &str, dependency: &str) -> Target {
+        let mut r = Target::new(
+            target,
+            true,
+            None,
+            &[dependency.to_string()],
+            self.recipes
+                .iter()
+                .map(|x| x.fix(target, dependency))
+                .collect(),
+        );
+        r.annotations = self.annotations.clone();
+        r.description = self.description.clone();
+        r.source = self.source.clone();
+        r
+    }
+
+    /// Kind of target for `--describe` and `--show-order`
+    fn kind(&self) -> &'static str {
+        if self.glob.is_some() {
+            "wildcard"
+        } else if self.dtg.is_some() {
+            "file"
+        } else if self.annotations.get("output").is_some() {
+            "phony with output file"
         } else {
-            false
+            "phony"
         }
     }
 
-    fn print_heading(&self) {
+    /// Files read by the recipes that make the target outdated if newer but are not dependencies
+    /// (`{inputs=PATH,...}`)
+    fn inputs(&self) -> impl Iterator<Item = &str> {
+        self.annotations
+            .get("inputs")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+    }
+
+    /// Absolute directories to prepend to `PATH` for the recipes (`{path+=DIR}`), relative to the
+    /// configuration file's directory
+    fn path_dirs(&self) -> Vec<PathBuf> {
+        let base = self.source.parent().unwrap_or(Path::new(""));
+        self.annotations
+            .get_all("path+")
+            .map(|dir| {
+                let dir = base.join(expanduser(dir).unwrap());
+                if dir.is_absolute() {
+                    dir
+                } else {
+                    std::env::current_dir()
+                        .expect("current directory")
+                        .join(dir)
+                }
+            })
+            .collect()
+    }
+
+    /// Named parameters declared by the target with their optional defaults
+    /// (`{params=NAME,NAME=DEFAULT,...}`)
+    fn params(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.annotations
+            .get("params")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(|x| match x.split_once('=') {
+                Some((name, default)) => (name, Some(default)),
+                None => (x, None),
+            })
+    }
+
+    /// Tags for selecting the target via `--tag` (`{tags=NAME,...}`)
+    fn tags(&self) -> impl Iterator<Item = &str> {
+        self.annotations
+            .get("tags")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+    }
+
+    /// Return true if a phony target (neither a file nor wildcard target)
+    fn is_phony(&self) -> bool {
+        self.dtg.is_none() && self.glob.is_none()
+    }
+
+    /// Return true if a special target (`.SHELLS`, `.ON_SUCCESS`, `.ON_FAILURE`)
+    fn is_special(&self) -> bool {
+        self.is_phony() && self.name.starts_with('.')
+    }
+
+    /// Return true if a phony target has no dependencies or recipes (special targets like `.PHONY`
+    /// are exempt)
+    fn is_empty_phony(&self) -> bool {
+        self.is_phony()
+            && !self.name.starts_with('.')
+            && self.dependencies.is_empty()
+            && self.recipes.is_empty()
+    }
+
+    /// Print the target's heading, unless `--no-headings`
+    fn print_heading(&self, cli: &Cli, state: &State) {
+        if cli.no_headings {
+            return;
+        }
+        let progress = state.progress();
         if self.dtg.is_some() {
-            print_file_target(&self.name);
+            print_file_target(&self.name, &progress);
+        } else {
+            print_target(&self.name, &progress);
+        }
+    }
+
+    /// Arguments after `--` or the target's `{args-default=...}` annotation
+    fn args(&self, cli: &Cli) -> String {
+        if cli.args.is_empty() {
+            self.annotations
+                .get("args-default")
+                .unwrap_or_default()
+                .to_string()
         } else {
-            print_target(&self.name);
+            cli.args.join(" ")
         }
     }
 
-    fn run(&self, dry_run: bool, verbose: u8, quiet: bool, script_mode: bool) {
-        if !quiet && (!self.recipes.is_empty() || verbose >= 2) {
-            self.print_heading();
+    /// Iterate the recipes of a stage: those scoped to the profile (`--profile`) if the target has
+    /// any for this stage, otherwise the unscoped recipes; of the alternative recipes, only those
+    /// of the selected alternative (`--alt`) or else the target's first alternative
+    fn stage<'a>(
+        &'a self,
+        stage: Stage,
+        profile: Option<&'a str>,
+        alt: Option<&'a str>,
+    ) -> impl Iterator<Item = &'a Recipe> {
+        let scoped = profile.is_some()
+            && self
+                .recipes
+                .iter()
+                .any(|x| x.stage == stage && x.profile.as_deref() == profile);
+        let alts = || self.recipes.iter().filter_map(|x| x.alt.as_deref());
+        let alt = alts().find(|x| Some(*x) == alt).or_else(|| alts().next());
+        self.recipes.iter().filter(move |x| {
+            x.stage == stage
+                && (x.alt.is_none() || x.alt.as_deref() == alt)
+                && if scoped {
+                    x.profile.as_deref() == profile
+                } else {
+                    x.profile.is_none()
+                }
+        })
+    }
+
+    fn run(&self, cli: &Cli, state: &mut State) -> Result<(), Failure> {
+        // Lint all recipes before running any (`--lint-recipes`)
+        if cli.lint_recipes {
+            if !program_exists(&cli.linter) {
+                let program = cli.linter.split_whitespace().next().unwrap_or_default();
+                error!(
+                    22,
+                    "ERROR: Linter `{program}` was not found; install it or use `--linter COMMAND`!"
+                );
+            }
+            let args = self.args(cli);
+            for recipe in &self.recipes {
+                recipe.lint(&self.name, &cli.linter, &args, state);
+            }
+        }
+
+        // Refuse to run commands that are not allowed (`--allow-commands`)
+        if let Some(allowed) = &cli.allow_commands {
+            for recipe in &self.recipes {
+                if let Some(program) = recipe.disallowed(allowed).first() {
+                    error!(
+                        17,
+                        "ERROR: Recipe for target `{}` runs a disallowed command: `{program}`!",
+                        self.name,
+                    );
+                }
+            }
+        }
+
+        if !cli.quiet && !cli.quiet_unless_fail && (!self.recipes.is_empty() || cli.verbose >= 2) {
+            self.print_heading(cli, state);
+        }
+        if self.recipes.is_empty() {
+            return Ok(());
+        }
+
+        let args = self.args(cli);
+
+        // Use the defaults of named parameters not given on the command line (`{params=...}`)
+        let mut defaults = vec![];
+        for (name, default) in self.params() {
+            if state.exports.contains_key(name) {
+                continue;
+            }
+            let Some(default) = default else {
+                state.problem(
+                    cli,
+                    21,
+                    format!(
+                        "Target `{}` requires parameter `{name}`; use `{name}=VALUE`",
+                        self.name
+                    ),
+                );
+                continue;
+            };
+            state.exports.insert(name.to_string(), default.to_string());
+            defaults.push(name);
+        }
+
+        // Recipes may write `key=value` lines to `$MKRS_EXPORT` for dependents to use as
+        // `{target.key}`
+        let export = std::env::temp_dir().join(format!(
+            "mkrs-{}-{}.env",
+            std::process::id(),
+            slug(&self.name)
+        ));
+        let _ = std::fs::remove_file(&export);
+        std::env::set_var("MKRS_EXPORT", &export);
+
+        // Prepend directories to `PATH` for the target's recipes (`{path+=DIR}`)
+        let path = std::env::var_os("PATH");
+        let dirs = self.path_dirs();
+        if !dirs.is_empty() {
+            let paths = dirs
+                .into_iter()
+                .chain(path.iter().flat_map(std::env::split_paths));
+            std::env::set_var("PATH", std::env::join_paths(paths).expect("PATH"));
+        }
+
+        let mut recipes = self
+            .stage(Stage::Setup, cli.profile.as_deref(), cli.alt.as_deref())
+            .chain(self.stage(Stage::Main, cli.profile.as_deref(), cli.alt.as_deref()));
+        let result = if cli.one_shell {
+            // Run consecutive recipes as one script so shell state carries between them
+            // (`-s --one-shell`)
+            combine(recipes)
+                .iter()
+                .try_for_each(|recipe| recipe.run(&self.name, &args, cli, state))
+        } else {
+            recipes.try_for_each(|recipe| recipe.run(&self.name, &args, cli, state))
+        };
+
+        // Teardown recipes run even if a setup or main recipe fails
+        let teardown = self
+            .stage(Stage::Teardown, cli.profile.as_deref(), cli.alt.as_deref())
+            .try_for_each(|recipe| recipe.run(&self.name, &args, cli, state));
+        let result = result.and(teardown);
+
+        // Write the buffered output to the target's log file (`--log-dir`)
+        if let Some(dir) = &cli.log_dir {
+            let path = dir.join(log_name(&self.name));
+            let written =
+                std::fs::create_dir_all(dir).and_then(|()| std::fs::write(&path, &state.output));
+            if written.is_err() {
+                error!(14, "ERROR: Could not write `{}`!", path.display());
+            }
+        }
+
+        // Show the buffered output of a failed target (`--quiet-unless-fail`)
+        if cli.quiet_unless_fail && result.is_err() {
+            self.print_heading(cli, state);
+            print!("{}", state.output);
+        }
+        state.output.clear();
+
+        std::env::remove_var("MKRS_EXPORT");
+        if let Some(path) = path {
+            std::env::set_var("PATH", path);
         }
-        for recipe in &self.recipes {
-            recipe.run(dry_run, verbose, quiet, script_mode);
+        state.import(&self.name, &export);
+        let _ = std::fs::remove_file(&export);
+        for name in defaults {
+            state.exports.shift_remove(name);
         }
+        result
     }
 }
 
 //--------------------------------------------------------------------------------------------------
 
+/// Combine consecutive recipes that can run in the same script (`--one-shell`)
+fn combine<'a>(recipes: impl Iterator<Item = &'a Recipe>) -> Vec<Recipe> {
+    let mut r: Vec<Recipe> = vec![];
+    for recipe in recipes {
+        match r.last_mut() {
+            Some(last) if last.can_combine(recipe) => {
+                last.commands.extend(recipe.commands.iter().cloned());
+            }
+            _ => r.push(recipe.clone()),
+        }
+    }
+    r
+}
+
+/// Expand a dependency path: `~` first, then the `{dirname}` variable (globbing comes last)
+fn expand_path(path: &str, dirname: &str) -> String {
+    expanduser(path)
+        .unwrap()
+        .display()
+        .to_string()
+        .replace("{dirname}", dirname)
+}
+
 fn glob_matcher(n: &str, is_glob: bool) -> Option<GlobMatcher> {
     is_glob.then(|| Glob::new(n).expect("glob").compile_matcher())
 }
 
+/// Prefix a relative path with the configuration file's directory, if anchored there
+fn anchor(path: &str, base: Option<&Path>) -> String {
+    match base {
+        Some(base) if Path::new(path).is_relative() => base.join(path).display().to_string(),
+        _ => path.to_string(),
+    }
+}
+
+/// Parse an include directive (`<!-- include PATH -->` or `<!-- include PATH if CONDITION -->`) and
+/// return the path if its condition is met
+fn parse_include(s: &str) -> Option<String> {
+    let s = s.trim().strip_prefix("<!--")?.strip_suffix("-->")?.trim();
+    let s = s.strip_prefix("include ")?.trim();
+    if let Some((path, condition)) = s.split_once(" if ") {
+        condition_met(condition.trim()).then(|| path.trim().to_string())
+    } else {
+        Some(s.to_string())
+    }
+}
+
+/// Evaluate a condition: `os:NAME`, `env:NAME` (is set), or `env:NAME=VALUE`
+fn condition_met(condition: &str) -> bool {
+    match condition.split_once(':') {
+        Some(("os", os)) => os == std::env::consts::OS,
+        Some(("env", var)) => match var.split_once('=') {
+            Some((name, value)) => std::env::var(name).is_ok_and(|x| x == value),
+            None => std::env::var_os(var).is_some(),
+        },
+        _ => {
+            error!(9, "ERROR: Invalid condition: `{condition}`!");
+        }
+    }
+}
+
+/// Return true if a configuration file is remote (a URL or `git:REPO#PATH`)
+fn is_remote(source: &str) -> bool {
+    source.starts_with("https://") || source.starts_with("http://") || source.starts_with("git:")
+}
+
+/// Fetch a remote configuration file via `curl` or a shallow `git clone`, falling back to the
+/// cached copy in `~/.cache/mkrs` if the fetch fails
+fn fetch(source: &str) -> Result<String> {
+    let cache = expanduser("~/.cache/mkrs")?.join(slug(source));
+
+    // Run `git` and `curl` directly, not via a shell, so the source is never parsed as shell code
+    if let Some(spec) = source.strip_prefix("git:") {
+        let Some((repo, path)) = spec.split_once('#') else {
+            return Err(anyhow!("Expected `git:REPO#PATH`, not `{source}`!"));
+        };
+        let mut git = std::process::Command::new("git");
+        if cache.exists() {
+            git.arg("-C").arg(&cache).args(["pull", "-q", "--ff-only"]);
+        } else {
+            git.args(["clone", "-q", "--depth", "1", "--", repo])
+                .arg(&cache);
+        }
+        let success = git.status().is_ok_and(|x| x.success());
+        let file = cache.join(path);
+        if !success && !file.exists() {
+            return Err(anyhow!("Could not fetch `{source}`!"));
+        }
+        Ok(std::fs::read_to_string(file)?)
+    } else {
+        let output = std::process::Command::new("curl")
+            .args(["-fsSL", source])
+            .stderr(Stdio::inherit())
+            .output();
+        match output {
+            Ok(output) if output.status.success() => {
+                let s = String::from_utf8_lossy(&output.stdout).to_string();
+                std::fs::create_dir_all(cache.parent().unwrap())?;
+                std::fs::write(&cache, &s)?;
+                Ok(s)
+            }
+            _ if cache.exists() => Ok(std::fs::read_to_string(&cache)?),
+            _ => Err(anyhow!("Could not fetch `{source}`!")),
+        }
+    }
+}
+
+/// Convert a target name to a lowercase, hyphenated slug
+fn slug(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Name of a target's log file (`--log-dir`): its slug, plus a hash of the name unless the name is
+/// its own slug, so that targets like `Build All` and `build-all` do not share a log file
+fn log_name(name: &str) -> String {
+    let slug = slug(name);
+    if slug == name {
+        format!("{slug}.log")
+    } else {
+        format!(
+            "{slug}-{:08x}.log",
+            fnv1a(FNV_OFFSET, name.as_bytes()) as u32
+        )
+    }
+}
+
+/// Select the pager for the readme: `$MKRS_PAGER`, `$PAGER`, `bat`, `less`, or none (print directly)
+#[cfg(unix)]
+fn readme_pager() -> Option<String> {
+    ["MKRS_PAGER", "PAGER"]
+        .iter()
+        .filter_map(|x| std::env::var(x).ok())
+        .chain([String::from("bat -pl md"), String::from("less")])
+        .find(|x| program_exists(x))
+}
+
+/// Failure hook (`.ON_FAILURE`), options, and the target being processed, for failures that exit
+/// immediately via `error!` while processing targets (missing dependency, lint failure, time limit,
+/// disallowed command, etc)
+static FAILURE_HOOK: Mutex<Option<(Target, Cli, String)>> = Mutex::new(None);
+
+/// Run the failure hook, if processing targets, before exiting with an error code; the
+/// `MKRS_FAILED_COMMAND` variable is not set since the failure is not a command's
+fn run_failure_hook(code: i32) {
+    let Some((hook, cli, target)) = FAILURE_HOOK.lock().ok().and_then(|mut x| x.take()) else {
+        return;
+    };
+    eprintln!();
+    std::env::set_var("MKRS_FAILED_TARGET", target);
+    std::env::set_var("MKRS_EXIT_CODE", code.to_string());
+    // A failed hook does not change the exit code
+    let _ = hook.run(&cli, &mut State::default());
+}
+
+/// Terminate a process's descendants, deepest first, so none keep running or hold the output open
+/// (`--time-limit`)
+fn terminate_descendants(pid: &str) {
+    let Ok(output) = std::process::Command::new("pgrep")
+        .args(["-P", pid])
+        .output()
+    else {
+        return;
+    };
+    for child in String::from_utf8_lossy(&output.stdout).split_whitespace() {
+        terminate_descendants(child);
+    }
+    let _ = std::process::Command::new("pkill")
+        .args(["-TERM", "-P", pid])
+        .status();
+}
+
+/// Return true if the program of a command exists (as a path or in `$PATH`)
+fn program_exists(command: &str) -> bool {
+    let Some(program) = command.split_whitespace().next() else {
+        return false;
+    };
+    if program.contains('/') {
+        Path::new(program).is_file()
+    } else {
+        std::env::var_os("PATH")
+            .is_some_and(|x| std::env::split_paths(&x).any(|x| x.join(program).is_file()))
+    }
+}
+
+/// Return true if a code block info string invokes a POSIX-like shell
+fn is_shell(command: &str) -> bool {
+    let program = command.split_whitespace().next().unwrap_or_default();
+    let program = program.rsplit('/').next().unwrap_or_default();
+    ["sh", "bash", "dash", "ksh", "zsh"].contains(&program)
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Update a 64-bit FNV-1a hash with bytes and a separator; unlike `DefaultHasher`, it is stable
+/// across platforms and Rust versions
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().chain([0xff].iter()).fold(hash, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Quote and escape a string for JSON output
+fn json_string(s: &str) -> String {
+    let mut r = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => r.push_str("\\\""),
+            '\\' => r.push_str("\\\\"),
+            '\n' => r.push_str("\\n"),
+            '\r' => r.push_str("\\r"),
+            '\t' => r.push_str("\\t"),
+            c if c.is_control() => r.push_str(&format!("\\u{:04x}", c as u32)),
+            c => r.push(c),
+        }
+    }
+    r.push('"');
+    r
+}
+
 /// Get the modified time of a file
-fn mtime(file: &str) -> std::time::SystemTime {
-    match std::fs::metadata(file) {
+fn mtime(file: &str, follow_symlinks: bool) -> std::time::SystemTime {
+    let metadata = if follow_symlinks {
+        std::fs::metadata(file)
+    } else if Path::new(file).exists() {
+        // Symlink's own modification time (a broken symlink is treated as missing)
+        std::fs::symlink_metadata(file)
+    } else {
+        return std::time::SystemTime::UNIX_EPOCH;
+    };
+    match metadata {
         Ok(m) => m.modified().expect("modified"),
         Err(_e) => std::time::SystemTime::UNIX_EPOCH,
     }
 }
 
+/// Print how a wildcard target was evaluated for a file target: whether it matched, the
+/// dependency derived from the target's stem, and whether it was applied (`--explain`)
+fn explain_wildcard(target: &str, wildcard: &str, dependency: Option<&str>, applied: bool) {
+    let Some(dependency) = dependency else {
+        ecprint!(
+            *DEBUG,
+            "Wildcard target `{wildcard}` does not match `{target}`\n"
+        );
+        return;
+    };
+    let result = if applied {
+        "applied"
+    } else {
+        "not applied (up to date)"
+    };
+    ecprint!(
+        *DEBUG,
+        "Wildcard target `{wildcard}` matches `{target}` with dependency `{dependency}`: {result}\n"
+    );
+}
+
 /// Return true if the reference file is newer than the file
-fn outdated(ref_file: &str, file: &str) -> bool {
-    mtime(ref_file) > mtime(file)
+fn outdated(ref_file: &str, file: &str, follow_symlinks: bool) -> bool {
+    mtime(ref_file, follow_symlinks) > mtime(file, follow_symlinks)
+}
+
+/// Print a target's commands and ask the user whether to run it, skip it, or abort (`--step`)
+fn step(target: &str, commands: &[&String]) -> bool {
+    if !std::io::stdin().is_terminal() {
+        error!(20, "ERROR: Option `--step` requires a terminal!");
+    }
+    println!("Next target `{target}`:");
+    for command in commands {
+        println!("$ {command}");
+    }
+    print!("Run it? [Enter: run, s: skip, q: abort] ");
+    std::io::stdout().flush().expect("flush");
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        error!(20, "ERROR: Aborted at target `{target}`!");
+    }
+    match answer.trim() {
+        "s" | "S" | "skip" => false,
+        "q" | "Q" | "quit" | "abort" => {
+            error!(20, "ERROR: Aborted at target `{target}`!");
+        }
+        _ => true,
+    }
+}
+
+/// Ask the user to confirm running a target
+fn confirm(target: &str) -> bool {
+    if !std::io::stdin().is_terminal() {
+        error!(
+            12,
+            "ERROR: Target `{target}` requires confirmation; use `--yes` when not in a terminal!"
+        );
+    }
+    print!("Run target `{target}`? [y/N] ");
+    std::io::stdout().flush().expect("flush");
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+//--------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Load a configuration from Markdown
+    fn config(s: &str) -> Config {
+        let mut config = Config::default();
+        config.load_markdown(s, Path::new("Makefile.md"), "crate", None);
+        config
+    }
+
+    #[test]
+    fn empty_phony() {
+        let config = config("# a\n\n# b\n\n* a\n\n# c\n\n```\ntrue\n```\n\n# `d`\n");
+        assert!(config.targets["a"].is_empty_phony());
+        assert!(!config.targets["b"].is_empty_phony());
+        assert!(!config.targets["c"].is_empty_phony());
+        assert!(!config.targets["d"].is_empty_phony());
+    }
+
+    #[test]
+    fn slugs() {
+        assert_eq!(slug("Build All"), "build-all");
+        assert_eq!(slug("  Deploy: staging / prod! "), "deploy-staging-prod");
+        assert_eq!(slug("test"), "test");
+    }
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(json_string("a"), "\"a\"");
+        assert_eq!(
+            json_string("say \"hi\"\\\n\t\u{1}"),
+            "\"say \\\"hi\\\"\\\\\\n\\t\\u0001\""
+        );
+    }
+
+    #[test]
+    fn includes() {
+        std::env::set_var("MKRS_TEST_INCLUDE", "yes");
+        assert_eq!(
+            parse_include("<!-- include a.md -->"),
+            Some(String::from("a.md"))
+        );
+        assert_eq!(
+            parse_include("<!-- include b.md if env:MKRS_TEST_INCLUDE -->"),
+            Some(String::from("b.md"))
+        );
+        assert_eq!(
+            parse_include("<!-- include c.md if env:MKRS_TEST_INCLUDE=no -->"),
+            None
+        );
+        assert_eq!(parse_include("<!-- comment -->"), None);
+        assert!(condition_met(&format!("os:{}", std::env::consts::OS)));
+        assert!(condition_met("env:MKRS_TEST_INCLUDE=yes"));
+        assert!(!condition_met("env:MKRS_TEST_UNSET"));
+    }
+
+    #[test]
+    fn heading_level() {
+        let mut config = Config {
+            heading_level: pd::HeadingLevel::H2,
+            ..Default::default()
+        };
+        let s = "# Tasks\n\nIntro\n\n## build\n\n* `main.c`\n\n```\ncc main.c\n```\n\n### Notes\n";
+        config.load_markdown(s, Path::new("README.md"), "crate", None);
+        assert_eq!(
+            config.targets.keys().collect::<Vec<_>>(),
+            ["build", "main.c"]
+        );
+        assert_eq!(config.targets["build"].recipes.len(), 1);
+    }
+
+    #[test]
+    fn regions() {
+        let config = config(
+            "# outside\n\n<!-- mkrs:start -->\n\n# a\n\n```\ntrue\n```\n\n<!-- mkrs:end -->\n\n\
+            # between\n\n<!-- mkrs:start -->\n\n# b\n\n<!-- mkrs:end -->\n\n# after\n",
+        );
+        assert_eq!(config.targets.keys().collect::<Vec<_>>(), ["a", "b"]);
+    }
+
+    #[test]
+    fn annotations() {
+        let (name, annotations) =
+            Annotations::parse("deploy {confirm} {shell=\"bash -l\"} {tags=a,b}");
+        assert_eq!(name, "deploy");
+        assert!(annotations.has("confirm"));
+        assert_eq!(annotations.get("confirm"), None);
+        assert_eq!(annotations.get("shell"), Some("bash -l"));
+        assert_eq!(annotations.get("tags"), Some("a,b"));
+        assert!(!annotations.has("once"));
+
+        let (name, annotations) = Annotations::parse("build");
+        assert_eq!(name, "build");
+        assert!(!annotations.has("literal"));
+
+        assert_eq!(
+            annotation_tokens("key=\"some value\", flag, tags=a,b"),
+            ["key=some value", "flag", "tags=a,b"]
+        );
+        assert_eq!(
+            Annotations::parse("x {key=“curly quotes”}").1.to_string(),
+            "{key=\"curly quotes\"}"
+        );
+    }
+
+    #[test]
+    fn unresolved() {
+        let config = config(
+            "# a\n\n* `b`\n\n```\necho {0} {target} {dirname} {args} ${HOME} {b.path}\necho \
+            {missing} '{quoted}' \"{double}\"\n```\n\n```python\nprint({'a': 1})\n```\n\n\
+            # `b`\n",
+        );
+        let recipes = &config.targets["a"].recipes;
+        let captures = HashSet::new();
+        assert_eq!(
+            recipes[0].unresolved(&config.targets, &captures),
+            ["{missing}", "{double}"]
+        );
+        assert!(recipes[1].unresolved(&config.targets, &captures).is_empty());
+        assert!(recipes[0]
+            .unresolved(&config.targets, &HashSet::from(["missing", "double"]))
+            .is_empty());
+    }
+
+    #[test]
+    fn captures() {
+        assert_eq!(
+            parse_capture("{capture VERSION}= git describe --tags"),
+            Some(("VERSION", "git describe --tags"))
+        );
+        assert_eq!(parse_capture("{capture X }=date"), Some(("X", "date")));
+        assert_eq!(parse_capture("echo {capture X}= date"), None);
+        assert_eq!(parse_capture("{capture X} = date"), None);
+    }
+
+    #[test]
+    fn to_markdown() {
+        let s = "# build {tags=ci}\n\nBuild it\n\n* `src/*.rs`\n* `\"[draft].md\"`\n* test\n\n\
+            ```\ncargo build\n```\n\n# test\n\n```bash {literal}\necho {0}\n```\n";
+        let markdown = config(s).to_markdown(None);
+        assert!(markdown.contains("* `src/*.rs`\n"));
+        assert!(!markdown.contains("src/main.rs"));
+        assert!(markdown.contains("{tags=ci}"));
+        assert!(markdown.contains("```bash {literal}\necho {0}\n```"));
+        assert_eq!(config(&markdown).to_markdown(None), markdown);
+    }
+
+    #[test]
+    fn remote_sources() {
+        assert!(is_remote("https://example.com/Makefile.md"));
+        assert!(is_remote("http://localhost:8000/Makefile.md"));
+        assert!(is_remote("git:https://github.com/qtfkwk/mkrs#Makefile.md"));
+        assert!(!is_remote("Makefile.md"));
+        assert!(!is_remote("tasks/*.md"));
+    }
+
+    #[test]
+    fn graph() {
+        let config = config("# a\n\n* b\n* `c\"d`\n\n# b\n\n* `c\"d`\n");
+        assert_eq!(
+            config.graph(GraphFormat::Mermaid),
+            "graph TD\n    n0[\"a\"]\n    n1[\"b\"]\n    n2[\"c#quot;d\"]\n    n0 --> n1\n    \
+            n0 --> n2\n    n1 --> n2\n"
+        );
+        assert_eq!(
+            config.graph(GraphFormat::Dot),
+            "digraph {\n    n0 [label=\"a\"];\n    n1 [label=\"b\"];\n    \
+            n2 [label=\"c\\\"d\"];\n    n0 -> n1;\n    n0 -> n2;\n    n1 -> n2;\n}\n"
+        );
+    }
+
+    #[test]
+    fn run_lines() {
+        assert_eq!(parse_run("{run:build}"), Some("build"));
+        assert_eq!(parse_run("  {run: build } "), Some("build"));
+        assert_eq!(parse_run("echo {run:build}"), None);
+        assert_eq!(parse_run("{run:build} && true"), None);
+    }
+
+    #[test]
+    fn task_list_dependencies() {
+        let config = config("# a\n\n- [ ] b\n- [x] `c.txt`\n- d\n");
+        assert_eq!(config.targets["a"].dependencies, ["b", "c.txt", "d"]);
+    }
+
+    #[test]
+    fn script_shells() {
+        assert_eq!(script_shell(None, 0, false), "bash -eo pipefail");
+        assert_eq!(script_shell(None, 1, false), "bash -xeo pipefail");
+        assert_eq!(script_shell(None, 0, true), "bash -leo pipefail");
+        assert_eq!(script_shell(None, 2, true), "bash -lxeo pipefail");
+        assert_eq!(script_shell(Some("python3"), 1, true), "python3");
+    }
+
+    #[test]
+    fn highlighting() {
+        use Token::*;
+        assert_eq!(
+            highlight("if [ -n \"$A\" ]; then echo {0} ${B} $1 # done", ""),
+            [
+                (Keyword, "if"),
+                (Plain, " [ -n "),
+                (String, "\"$A\""),
+                (Plain, " ]; "),
+                (Keyword, "then"),
+                (Plain, " echo "),
+                (Variable, "{0}"),
+                (Plain, " "),
+                (Variable, "${B}"),
+                (Plain, " "),
+                (Variable, "$1"),
+                (Plain, " "),
+                (Comment, "# done"),
+            ],
+        );
+        assert_eq!(
+            highlight("echo a#b 'c \\' ifx", "bash"),
+            [(Plain, "echo a#b "), (String, "'c \\'"), (Plain, " ifx")],
+        );
+        assert_eq!(
+            highlight("print(\"a \\\" b\") # $x", "python"),
+            [
+                (Plain, "print("),
+                (String, "\"a \\\" b\""),
+                (Plain, ") "),
+                (Comment, "# $x"),
+            ],
+        );
+        assert_eq!(
+            highlight("let x = 1; // if", "rust"),
+            [(Plain, "let x = 1; "), (Comment, "// if")],
+        );
+    }
+
+    #[test]
+    fn ignore() {
+        let path = std::env::temp_dir().join(format!("mkrsignore-{}", std::process::id()));
+        std::fs::write(&path, "# comment\ntarget/\n/vendor\n*.bak\n!keep.bak\n[\n").unwrap();
+        let mut warnings = vec![];
+        let ignore = Ignore::load(&path, &mut warnings);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("Invalid pattern `[`"));
+        assert!(ignore.is_ignored("target"));
+        assert!(ignore.is_ignored("./target/debug/mkrs"));
+        assert!(ignore.is_ignored("sub/target/x.rs"));
+        assert!(ignore.is_ignored("vendor/x.rs"));
+        assert!(!ignore.is_ignored("sub/vendor/x.rs"));
+        assert!(ignore.is_ignored("notes.bak"));
+        assert!(!ignore.is_ignored("keep.bak"));
+        assert!(!ignore.is_ignored("src/main.rs"));
+        assert!(!Ignore::default().is_ignored("target"));
+    }
+
+    #[test]
+    fn fingerprints() {
+        assert_eq!(fnv1a(FNV_OFFSET, b"abc"), fnv1a(FNV_OFFSET, b"abc"));
+        assert_ne!(
+            fnv1a(fnv1a(FNV_OFFSET, b"ab"), b"c"),
+            fnv1a(fnv1a(FNV_OFFSET, b"a"), b"bc")
+        );
+
+        let input = std::env::temp_dir().join(format!("mkrs-input-{}", std::process::id()));
+        std::fs::write(&input, "one").unwrap();
+        let s = format!(
+            "# a {{inputs={}}}\n\n* b\n\n```\necho a\n```\n\n# b\n\n```\necho b\n```\n",
+            input.display(),
+        );
+        let fingerprint = |s: &str| config(s).fingerprint("a", &mut HashMap::new());
+        let original = fingerprint(&s);
+        assert_eq!(fingerprint(&s), original);
+        assert_ne!(fingerprint(&s.replace("echo a", "echo A")), original);
+        assert_ne!(fingerprint(&s.replace("echo b", "echo B")), original);
+        std::fs::write(&input, "two").unwrap();
+        assert_ne!(fingerprint(&s), original);
+        std::fs::remove_file(&input).unwrap();
+    }
+
+    #[test]
+    fn log_names() {
+        assert_eq!(log_name("build-all"), "build-all.log");
+        assert!(log_name("Build All").starts_with("build-all-"));
+        assert_ne!(log_name("Build All"), log_name("build all"));
+        assert_eq!(log_name("Build All"), log_name("Build All"));
+    }
+
+    #[test]
+    fn lost_state() {
+        let config = config(
+            "# a\n\n```\ncd sub\nexport X=1\nX=1\ncd sub && make\nA=1 make\necho hi\n\
+            cd last\n```\n\n```bash\ncd sub\nmake\n```\n",
+        );
+        let recipes = &config.targets["a"].recipes;
+        assert_eq!(recipes[0].lost_state(), ["cd sub", "export X=1", "X=1"]);
+        assert!(recipes[1].lost_state().is_empty());
+    }
+
+    #[test]
+    fn themes() {
+        assert_eq!(Theme::Dark.resolve(), Theme::Dark);
+        assert_eq!(Theme::Light.resolve(), Theme::Light);
+        for (colorfgbg, theme) in [
+            ("0;15", Theme::Light),
+            ("0;7", Theme::Light),
+            ("15;0", Theme::Dark),
+            ("12;default;8", Theme::Dark),
+            ("", Theme::Dark),
+        ] {
+            std::env::set_var("COLORFGBG", colorfgbg);
+            assert_eq!(Theme::Auto.resolve(), theme);
+        }
+        std::env::remove_var("COLORFGBG");
+        assert_eq!(Theme::Auto.resolve(), Theme::Dark);
+    }
+
+    #[test]
+    fn params() {
+        assert_eq!(parse_param("env=staging"), Some(("env", "staging")));
+        assert_eq!(parse_param("region="), Some(("region", "")));
+        assert_eq!(parse_param("build"), None);
+        assert_eq!(parse_param("=x"), None);
+        assert_eq!(parse_param("a.b=x"), None);
+
+        let config = config("# deploy {params=env,region=eu}\n");
+        assert_eq!(
+            config.targets["deploy"].params().collect::<Vec<_>>(),
+            [("env", None), ("region", Some("eu"))]
+        );
+    }
 }
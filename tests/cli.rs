@@ -0,0 +1,1595 @@
+//! Process-level tests: each test writes a `Makefile.md` to a scratch directory and runs `mkrs` in
+//! it
+
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static SCRATCH: AtomicUsize = AtomicUsize::new(0);
+
+/// Create an empty scratch directory
+fn scratch() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "mkrs-test-{}-{}",
+        std::process::id(),
+        SCRATCH.fetch_add(1, Ordering::SeqCst),
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Create a scratch directory with a `Makefile.md`
+fn project(makefile: &str) -> PathBuf {
+    let dir = scratch();
+    write(&dir, "Makefile.md", makefile);
+    dir
+}
+
+/// Write a file in a directory, creating its parent directories
+fn write(dir: &Path, path: &str, contents: &str) {
+    let path = dir.join(path);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, contents).unwrap();
+}
+
+/// Write an executable script in a directory
+fn script(dir: &Path, path: &str, contents: &str) {
+    use std::os::unix::fs::PermissionsExt;
+    write(dir, path, contents);
+    std::fs::set_permissions(dir.join(path), std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+/// Read a file in a directory
+fn read(dir: &Path, path: &str) -> String {
+    std::fs::read_to_string(dir.join(path)).unwrap()
+}
+
+/// Run mkrs in a directory without a terminal
+fn mkrs(dir: &Path, args: &[&str]) -> Output {
+    mkrs_env(dir, args, &[])
+}
+
+/// Run mkrs in a directory without a terminal and with environment variables
+fn mkrs_env(dir: &Path, args: &[&str], env: &[(&str, &str)]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_mkrs"))
+        .current_dir(dir)
+        .args(args)
+        .env_remove("CLICOLOR_FORCE")
+        .env_remove("COLORFGBG")
+        .envs(env.iter().copied())
+        .stdin(Stdio::null())
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+#[test]
+fn fail_on_missing_recipe() {
+    let dir = project("# empty\n\n# full\n\n```\ntrue\n```\n");
+    let output = mkrs(&dir, &["--fail-on-missing-recipe", "empty"]);
+    assert_eq!(output.status.code(), Some(7));
+    assert!(stderr(&output).contains("Target `empty` has no dependencies or recipes"));
+
+    let output = mkrs(&dir, &["--fail-on-missing-recipe", "full"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("$ true"));
+    assert!(mkrs(&dir, &["empty"]).status.success());
+}
+
+#[test]
+fn lint_recipes() {
+    let dir = project("# bad\n\n```\ncd $DIR\n```\n\n# good\n\n```\ncd \"$DIR\"\n```\n");
+
+    // Stand-in for shellcheck that rejects an unquoted `$DIR`
+    script(&dir, "lint", "#!/bin/sh\n! grep -q ' \\$DIR'\n");
+    let lint = dir.join("lint").display().to_string();
+
+    let output = mkrs(&dir, &["--lint-recipes", "--linter", &lint, "bad"]);
+    assert_eq!(output.status.code(), Some(8));
+    assert!(stderr(&output).contains("Recipe for target `bad` failed linting"));
+    assert!(!stdout(&output).contains("$ cd"));
+
+    let output = mkrs(&dir, &["--lint-recipes", "--linter", &lint, "good"]);
+    assert!(output.status.success());
+
+    let output = mkrs(
+        &dir,
+        &["--lint-recipes", "--linter", "nonexistent-linter", "good"],
+    );
+    assert_eq!(output.status.code(), Some(22));
+    assert!(stderr(&output).contains("Linter `nonexistent-linter` was not found"));
+}
+
+#[test]
+fn target_slug() {
+    let dir = project("# Build All\n\n```\necho built\n```\n");
+    for name in ["Build All", "build-all"] {
+        let output = mkrs(&dir, &[name]);
+        assert!(output.status.success());
+        assert!(stdout(&output).contains("# Build All"));
+        assert!(stdout(&output).contains("built"));
+    }
+}
+
+#[test]
+fn report_json() {
+    let dir = project(
+        "# all\n\n* fail\n* pass\n\n# fail\n\n```\nexit 3\n```\n\n# pass\n\n```\ntrue\n```\n",
+    );
+    let output = mkrs(&dir, &["-k", "--report", "json=report.json"]);
+    assert!(!output.status.success());
+    let report = read(&dir, "report.json");
+    assert!(report.contains("\"success\": false"));
+    assert!(report.contains("\"name\": \"fail\", \"status\": \"failed\""));
+    assert!(report.contains("\"code\": 3, \"command\": \"exit 3\""));
+    assert!(report.contains("\"name\": \"pass\", \"status\": \"succeeded\""));
+}
+
+#[test]
+fn conditional_include() {
+    let dir = project("# a\n\n```\ntrue\n```\n\n<!-- include ci.md if env:MKRS_CI=1 -->\n");
+    write(&dir, "ci.md", "# ci\n\n```\necho ci\n```\n");
+
+    let output = mkrs_env(&dir, &["ci"], &[("MKRS_CI", "1")]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("echo ci"));
+
+    let output = mkrs_env(&dir, &["ci"], &[("MKRS_CI", "0")]);
+    assert_eq!(output.status.code(), Some(5));
+}
+
+#[test]
+fn readme_without_pager() {
+    let dir = scratch();
+    let output = mkrs_env(
+        &dir,
+        &["-r"],
+        &[("MKRS_PAGER", ""), ("PAGER", ""), ("PATH", "")],
+    );
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("# About"));
+}
+
+#[test]
+fn create_dir() {
+    let dir = project("# pwd\n\n```\npwd\n```\n");
+    let args = ["-C", "new/sub", "-f", "../../Makefile.md"];
+
+    let output = mkrs(&dir, &args);
+    assert!(!output.status.success());
+    assert!(!dir.join("new/sub").exists());
+
+    let output = mkrs(&dir, &[&args[..], &["--create-dir"]].concat());
+    assert!(output.status.success());
+    assert!(dir.join("new/sub").is_dir());
+    assert!(stdout(&output).contains("new/sub\n"));
+}
+
+#[test]
+fn hooks() {
+    let dir = project(
+        "# ok\n\n```\ntrue\n```\n\n# fail\n\n```\nexit 2\n```\n\n\
+        # .ON_SUCCESS\n\n```\necho success-hook\n```\n\n\
+        # .ON_FAILURE\n\n```\necho \"failure-hook $MKRS_FAILED_TARGET $MKRS_EXIT_CODE\"\n```\n",
+    );
+
+    assert_eq!(stdout(&mkrs(&dir, &["-l"])), "* ok\n* fail\n\n");
+
+    let output = mkrs(&dir, &["ok"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output).matches("\nsuccess-hook\n").count(), 1);
+    assert!(!stdout(&output).contains("failure-hook"));
+
+    let output = mkrs(&dir, &["fail"]);
+    assert!(!output.status.success());
+    assert!(stdout(&output).contains("\nfailure-hook fail 2\n"));
+    assert!(!stdout(&output).contains("success-hook"));
+
+    // Failures that exit immediately also run the failure hook
+    write(
+        &dir,
+        "Makefile.md",
+        "# `out.txt`\n\n* `missing.txt`\n\n```\ntouch out.txt\n```\n\n\
+        # sleep\n\n```\nsleep 5\n```\n\n\
+        # .ON_FAILURE\n\n```\necho \"failure-hook $MKRS_FAILED_TARGET $MKRS_EXIT_CODE\"\n```\n",
+    );
+    for (args, code, target) in [
+        (&["out.txt"][..], 3, "missing.txt"),
+        (&["--allow-commands", "echo", "sleep"], 17, "sleep"),
+        (&["--time-limit", "1", "sleep"], 16, "sleep"),
+    ] {
+        let output = mkrs(&dir, args);
+        assert_eq!(output.status.code(), Some(code));
+        assert!(stdout(&output).contains(&format!("\nfailure-hook {target} {code}\n")));
+    }
+}
+
+#[test]
+fn pick_without_terminal() {
+    let dir = project("# a\n\n```\ntrue\n```\n");
+    let output = mkrs(&dir, &["--pick"]);
+    assert_eq!(output.status.code(), Some(10));
+    assert!(stderr(&output).contains("`--pick` requires a terminal"));
+}
+
+#[test]
+fn heading_level_2() {
+    let dir = project("# Tasks\n\n## build\n\n```\necho built\n```\n");
+    let output = mkrs(&dir, &["--heading-level", "2", "build"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("built"));
+    assert_eq!(mkrs(&dir, &["build"]).status.code(), Some(5));
+}
+
+#[test]
+fn print_deps() {
+    let dir = project("# all\n\n* c\n* a\n* `b.txt`\n\n# a\n\n* d\n\n# c\n\n# d\n");
+    let output = mkrs(&dir, &["--print-deps", "all"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "c\na\nb.txt\n");
+}
+
+#[test]
+fn literal_recipe() {
+    let dir = project(
+        "# a\n\n* b\n\n```\necho {0} {target}\n```\n\n```bash {literal}\necho {0} {target}\n```\n\
+        \n# b\n",
+    );
+    let output = mkrs(&dir, &["a"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("\nb a\n"));
+    assert!(stdout(&output).contains("\n{0} {target}\n"));
+}
+
+#[test]
+fn config_dir() {
+    let dir = project("");
+    write(
+        &dir,
+        "sub/Makefile.md",
+        "# show\n\n* `in.txt`\n\n```\necho {0} {dirname}\n```\n",
+    );
+    write(&dir, "sub/in.txt", "");
+
+    let output = mkrs(&dir, &["-f", "sub/Makefile.md"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("File `in.txt` does not exist"));
+
+    let output = mkrs(&dir, &["-f", "sub/Makefile.md", "--config-dir", "config"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("\nsub/in.txt sub\n"));
+}
+
+#[test]
+fn max_depth() {
+    let dir = project("# a\n\n* b\n\n# b\n\n* c\n\n# c\n\n```\necho c\n```\n");
+
+    let output = mkrs(&dir, &["--max-depth", "1"]);
+    assert_eq!(output.status.code(), Some(11));
+    assert!(stderr(&output).contains("Maximum dependency depth (1) exceeded at target `c`"));
+    assert!(!stdout(&output).contains("$ echo c"));
+
+    assert!(mkrs(&dir, &["--max-depth", "2"]).status.success());
+}
+
+#[test]
+fn commands_table() {
+    let dir = project("# a\n\n```\ntrue\necho 'a|b'\n```\n\n# b\n\n```\nexit 4\n```\n");
+    let output = mkrs(&dir, &["-vv", "-k", "a", "b"]);
+    let stdout = stdout(&output);
+    assert!(stdout.contains("Target | Command | Code | Duration\n"));
+    assert!(stdout.contains("\na | true | 0 | "));
+    assert!(stdout.contains("\na | echo 'a\\|b' | 0 | "));
+    assert!(stdout.contains("\nb | exit 4 | 4 | "));
+}
+
+#[test]
+fn confirm() {
+    let dir = project("# deploy {confirm}\n\n```\necho deployed\n```\n");
+
+    let output = mkrs(&dir, &["deploy"]);
+    assert_eq!(output.status.code(), Some(12));
+    assert!(!stdout(&output).contains("deployed"));
+
+    let output = mkrs(&dir, &["--yes", "deploy"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("\ndeployed\n"));
+}
+
+#[test]
+fn exports() {
+    let dir = project(
+        "# b\n\n* a\n\n```\necho \"using {a.path}\"\n```\n\n# a\n\n```\necho path=out/a.bin \
+        >> \"$MKRS_EXPORT\"\n```\n",
+    );
+    let output = mkrs(&dir, &["b"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("\nusing out/a.bin\n"));
+}
+
+#[test]
+fn list_sorted() {
+    let dir = project("# c\n\n# a\n\n# `b.txt`\n\n```\ntouch b.txt\n```\n");
+    assert_eq!(stdout(&mkrs(&dir, &["-l"])), "* c\n* a\n* `b.txt`\n\n");
+    assert_eq!(
+        stdout(&mkrs(&dir, &["-l", "--sort"])),
+        "* a\n* `b.txt`\n* c\n\n"
+    );
+}
+
+#[test]
+fn check_unresolved() {
+    let dir = project("# a\n\n```\necho {missing}\n```\n\n# b\n\n```\necho {target}\n```\n");
+    let output = mkrs(&dir, &["--check"]);
+    assert_eq!(output.status.code(), Some(13));
+    assert!(stderr(&output).contains("Unresolved `{missing}` in recipe for target `a`"));
+    assert!(!stderr(&output).contains("target `b`"));
+    assert!(stdout(&output).is_empty());
+}
+
+#[test]
+fn color_always_piped() {
+    let dir = project("# a\n\n```\ntrue\n```\n\n# b\n\n* a\n\n```\ntrue\n```\n");
+    let output = stdout(&mkrs(&dir, &["--color", "always", "b"]));
+    let headings = output
+        .lines()
+        .filter(|x| x.contains("] a") || x.contains("] b"))
+        .collect::<Vec<_>>();
+    assert_eq!(headings.len(), 2);
+    assert!(headings.iter().all(|x| x.starts_with('\x1b')));
+
+    let output = stdout(&mkrs(&dir, &["b"]));
+    assert!(!output.contains('\x1b'));
+}
+
+#[test]
+fn args_default() {
+    let dir = project("# test {args-default=--quick}\n\n```\necho args: {args}\n```\n");
+    assert!(stdout(&mkrs(&dir, &["test"])).contains("\nargs: --quick\n"));
+    assert!(stdout(&mkrs(&dir, &["test", "--", "--all", "-v"])).contains("\nargs: --all -v\n"));
+}
+
+#[test]
+fn complete_targets() {
+    let dir =
+        project("# build\n\nBuild it\n\n# test\n\n```\ntrue\n```\n\n# .SHELLS {py=python3}\n");
+    let output = mkrs(&dir, &["--complete-targets"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "build\tBuild it\ntest\t\n");
+}
+
+#[test]
+fn wide_diamond_graph() {
+    let n = 500;
+    let mut makefile = String::from("# top\n\n");
+    for i in 0..n {
+        makefile.push_str(&format!("* m{i}\n"));
+    }
+    for i in 0..n {
+        makefile.push_str(&format!("\n# m{i}\n\n* base\n"));
+    }
+    makefile.push_str("\n# base\n\n```\necho base\n```\n");
+    let dir = project(&makefile);
+
+    let start = std::time::Instant::now();
+    let output = mkrs(&dir, &["top"]);
+    assert!(start.elapsed() < std::time::Duration::from_secs(10));
+    assert!(output.status.success());
+    assert_eq!(stdout(&output).matches("$ echo base").count(), 1);
+}
+
+#[test]
+fn deep_diamond_graph() {
+    // 40 layers of 2 file targets, each depending on both targets of the next layer
+    let layers = 40;
+    let mut makefile = String::new();
+    for i in 0..layers {
+        for side in ["a", "b"] {
+            makefile.push_str(&format!("# `{i}{side}.txt`\n\n"));
+            if i + 1 < layers {
+                makefile.push_str(&format!("* `{0}a.txt`\n* `{0}b.txt`\n\n", i + 1));
+            }
+            makefile.push_str(&format!("```\ntouch {i}{side}.txt\n```\n\n"));
+        }
+    }
+    let dir = project(&makefile);
+
+    let start = std::time::Instant::now();
+    let output = mkrs(&dir, &["0a.txt"]);
+    assert!(start.elapsed() < std::time::Duration::from_secs(10));
+    assert!(output.status.success());
+    assert_eq!(stdout(&output).matches("$ touch").count(), 2 * layers - 1);
+
+    // Up to date
+    let start = std::time::Instant::now();
+    let output = mkrs(&dir, &["0a.txt"]);
+    assert!(start.elapsed() < std::time::Duration::from_secs(10));
+    assert!(output.status.success());
+    assert!(!stdout(&output).contains("$ touch"));
+}
+
+#[test]
+fn heading_shell() {
+    let dir = project(
+        "# a {shell=sh}\n\n```\necho \"$0 first\"\n```\n\n```\necho \"$0 second\"\n```\n\n\
+        ```bash\necho \"$0 third\"\n```\n",
+    );
+    let output = mkrs(&dir, &["a"]);
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    assert!(stdout.contains("\nsh first\n"));
+    assert!(stdout.contains("\nsh second\n"));
+    assert!(stdout.contains("\nbash third\n"));
+}
+
+#[test]
+fn all_phony_targets() {
+    let dir = project(
+        "# a\n\n* b\n\n```\necho run-a\n```\n\n# b\n\n```\necho run-b\n```\n\n# c\n\n\
+        ```\necho run-c\n```\n\n# `file`\n\n```\necho run-file\n```\n\n# .ON_SUCCESS\n",
+    );
+    let output = mkrs(&dir, &["--all"]);
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    for name in ["a", "b", "c"] {
+        assert_eq!(stdout.matches(&format!("\nrun-{name}\n")).count(), 1);
+    }
+    assert!(!stdout.contains("run-file"));
+}
+
+#[test]
+fn all_keep_going() {
+    let dir = project(
+        "# a\n\n```\nexit 1\n```\n\n# b\n\n* a\n\n```\necho run-b\n```\n\n\
+        # c\n\n```\necho run-c\n```\n\n# d\n\n```\necho run-d\n```\n",
+    );
+    let output = mkrs(&dir, &["--all", "-k"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = stdout(&output);
+    assert!(!stdout.contains("run-b"));
+    assert!(stdout.contains("\nrun-c\n"));
+    assert!(stdout.contains("\nrun-d\n"));
+}
+
+#[test]
+fn check_noop_recipe() {
+    let dir = project("# a\n\n```\n# TODO\n\n```\n\n# b\n\n```\ntrue\n```\n");
+    let output = mkrs(&dir, &["--check"]);
+    assert!(output.status.success());
+    assert!(stderr(&output).contains("Recipe for target `a` is empty or only has comments"));
+    assert!(!stderr(&output).contains("target `b`"));
+}
+
+#[test]
+fn dry_run_problems() {
+    let dir = project(
+        "# a\n\n* `missing.txt`\n\n```\necho {nope}\n```\n\n# b\n\n```\nawk '{print}' x | \
+        sed 's/{a}/b/'\n```\n",
+    );
+    let output = mkrs(&dir, &["-n", "a", "b", "zzz"]);
+    assert_eq!(output.status.code(), Some(13));
+    let stderr = stderr(&output);
+    assert!(stderr.contains("Unresolved `{nope}` in recipe for target `a`"));
+    assert!(stderr.contains("File `missing.txt` does not exist"));
+    assert!(stderr.contains("Invalid target: `zzz`"));
+    assert!(stderr.contains("Dry run found 3 problem(s)"));
+    assert!(!stderr.contains("target `b`"));
+    assert!(stdout(&output).contains("awk '{print}' x"));
+}
+
+#[test]
+fn capture() {
+    let dir = project(
+        "# a\n\n```\n{capture GREETING}= echo hello\necho \"{GREETING}, world\"\n```\n\n# b\n\n\
+        * a\n\n```\necho \"again {GREETING}\"\n```\n",
+    );
+    let output = mkrs(&dir, &["b"]);
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    assert!(stdout.contains("\nhello, world\n"));
+    assert!(stdout.contains("\nagain hello\n"));
+}
+
+#[test]
+fn strict() {
+    let dir = project("# old {deprecated=\"use new\"}\n\n```\necho ran\n```\n");
+
+    let output = mkrs(&dir, &["old"]);
+    assert!(output.status.success());
+    assert!(stderr(&output).contains("use new"));
+    assert!(stdout(&output).contains("\nran\n"));
+
+    let output = mkrs(&dir, &["--strict", "old"]);
+    assert_eq!(output.status.code(), Some(13));
+    assert!(stderr(&output).contains("use new"));
+    assert!(!stdout(&output).contains("\nran\n"));
+
+    write(&dir, "Makefile.md", "# a\n\n```\n# nothing\n```\n");
+    assert!(mkrs(&dir, &["--check"]).status.success());
+    assert_eq!(mkrs(&dir, &["--check", "--strict"]).status.code(), Some(13));
+}
+
+#[test]
+fn changed() {
+    let dir = project("# `out.txt`\n\n* `a.txt`\n* `b.txt`\n\n```\necho rebuilt\n```\n");
+    write(&dir, "a.txt", "");
+    write(&dir, "b.txt", "");
+    write(&dir, "out.txt", "");
+
+    let output = mkrs(&dir, &["out.txt"]);
+    assert!(output.status.success());
+    assert!(!stdout(&output).contains("rebuilt"));
+
+    let output = mkrs(&dir, &["--changed", "b.txt", "out.txt"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("\nrebuilt\n"));
+}
+
+#[test]
+fn setup_teardown() {
+    let dir = project(
+        "# test\n\n```\necho main\nexit 1\n```\n\n```sh {teardown}\necho teardown\n```\n\n\
+        ```{setup}\necho setup\n```\n",
+    );
+    let output = mkrs(&dir, &["test"]);
+    assert!(!output.status.success());
+    let stdout = stdout(&output);
+    let setup = stdout.find("\nsetup\n").unwrap();
+    let main = stdout.find("\nmain\n").unwrap();
+    let teardown = stdout.find("\nteardown\n").unwrap();
+    assert!(setup < main && main < teardown);
+}
+
+#[test]
+fn format_config() {
+    let dir = project(
+        "# build   {once}\nBuild it\n- [ ] `*.c`\n- test\n```\ncc *.c\n```\n\
+        # test\n```\ntrue\n```\n",
+    );
+    write(&dir, "main.c", "");
+    let output = mkrs(&dir, &["--format-config"]);
+    assert!(output.status.success());
+    let formatted = stdout(&output);
+    assert!(formatted.contains("* `*.c`\n"));
+
+    write(&dir, "Makefile.md", &formatted);
+    assert_eq!(stdout(&mkrs(&dir, &["--format-config"])), formatted);
+}
+
+#[test]
+fn exclude() {
+    let dir = project(
+        "# all\n\n* a\n* b\n\n# a\n\n* only-a\n* shared\n\n```\necho run-a\n```\n\n# b\n\n\
+        * shared\n\n```\necho run-b\n```\n\n# only-a\n\n```\necho run-only-a\n```\n\n\
+        # shared\n\n```\necho run-shared\n```\n",
+    );
+    let output = mkrs(&dir, &["--exclude", "a", "all"]);
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    assert!(!stdout.contains("run-a"));
+    assert!(!stdout.contains("run-only-a"));
+    assert!(stdout.contains("\nrun-shared\n"));
+    assert!(stdout.contains("\nrun-b\n"));
+}
+
+#[cfg(unix)]
+#[test]
+fn symlink_dependency() {
+    let dir = project("# `out`\n\n* `link`\n\n```\necho rebuilt\n```\n");
+    write(&dir, "real", "");
+    write(&dir, "out", "");
+    std::os::unix::fs::symlink("real", dir.join("link")).unwrap();
+    let touch = |args: &[&str]| {
+        assert!(Command::new("touch")
+            .current_dir(&dir)
+            .args(args)
+            .status()
+            .unwrap()
+            .success());
+    };
+    touch(&["-h", "-d", "2020-01-01", "link"]);
+    touch(&["-d", "2020-01-02", "out"]);
+    touch(&["-d", "2020-01-03", "real"]);
+
+    assert!(stdout(&mkrs(&dir, &["out"])).contains("\nrebuilt\n"));
+    assert!(!stdout(&mkrs(&dir, &["--no-follow-symlinks", "out"])).contains("rebuilt"));
+}
+
+#[test]
+fn list_invalid_targets() {
+    let dir = project("# a\n\n```\ntrue\n```\n");
+    let output = mkrs(&dir, &["-l", "x", "a", "y"]);
+    assert_eq!(output.status.code(), Some(5));
+    assert!(stderr(&output).contains("Invalid target(s): `x`, `y`"));
+    assert!(mkrs(&dir, &["-l", "a"]).status.success());
+}
+
+#[cfg(unix)]
+#[test]
+fn nice() {
+    let dir = project("# a\n\n```\nnice\n```\n");
+    let output = Command::new("nice").output().unwrap();
+    let niceness = stdout(&output).trim().parse::<i32>().unwrap();
+    let output = mkrs(&dir, &["--nice", "5"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains(&format!("\n{}\n", (niceness + 5).min(19))));
+}
+
+#[test]
+fn expect_fail() {
+    let dir = project(
+        "# fails {expect-fail}\n\n```\nfalse\n```\n\n# passes {expect-fail}\n\n```\ntrue\n```\n",
+    );
+    assert!(mkrs(&dir, &["fails"]).status.success());
+    assert!(!mkrs(&dir, &["passes"]).status.success());
+}
+
+#[test]
+fn remote_config() {
+    if Command::new("curl").arg("--version").output().is_err() {
+        return;
+    }
+
+    // Serve the configuration over HTTP
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/Makefile.md", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        use std::io::{Read, Write};
+        let body = "# remote\n\n```\necho from-remote\n```\n";
+        for mut stream in listener.incoming().map_while(Result::ok) {
+            let _ = stream.read(&mut [0; 4096]);
+            let _ = write!(
+                stream,
+                "HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len(),
+            );
+        }
+    });
+
+    let dir = scratch();
+    let home = dir.display().to_string();
+    let output = mkrs_env(&dir, &["-f", &url, "remote"], &[("HOME", &home)]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("requires `--allow-remote`"));
+
+    let output = mkrs_env(
+        &dir,
+        &["--allow-remote", "-f", &url, "remote"],
+        &[("HOME", &home)],
+    );
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("\nfrom-remote\n"));
+
+    // A quote in the source is not interpreted by a shell
+    for source in [
+        "http://127.0.0.1:1/'; touch pwned; '",
+        "git:'; touch pwned; '#x.md",
+    ] {
+        let output = mkrs_env(&dir, &["--allow-remote", "-f", source], &[("HOME", &home)]);
+        assert!(!output.status.success());
+        assert!(!dir.join("pwned").exists());
+    }
+}
+
+#[test]
+fn literal_dependency_path() {
+    let dir = project("# `out.txt`\n\n* `\"[draft].md\"`\n\n```\ncat '{0}' > out.txt\n```\n");
+    write(&dir, "[draft].md", "draft\n");
+    write(&dir, "d.md", "not a draft\n");
+    let output = mkrs(&dir, &["out.txt"]);
+    assert!(output.status.success());
+    assert_eq!(read(&dir, "out.txt"), "draft\n");
+}
+
+#[test]
+fn profiles() {
+    let dir =
+        project("# build\n\n```\necho debug\n```\n\n```{profile=release}\necho release\n```\n");
+    let output = stdout(&mkrs(&dir, &["build"]));
+    assert!(output.contains("\ndebug\n"));
+    assert!(!output.contains("\nrelease\n"));
+
+    let output = stdout(&mkrs(&dir, &["--profile", "release", "build"]));
+    assert!(output.contains("\nrelease\n"));
+    assert!(!output.contains("\ndebug\n"));
+}
+
+#[test]
+fn progress() {
+    let dir = project(
+        "# all\n\n* a\n* `done.txt`\n* b\n\n```\ntrue\n```\n\n# a\n\n```\ntrue\n```\n\n\
+        # b\n\n```\ntrue\n```\n\n# `done.txt`\n\n```\ntouch done.txt\n```\n",
+    );
+    write(&dir, "done.txt", "");
+    let output = stdout(&mkrs(&dir, &["all"]));
+    let headings = output
+        .lines()
+        .filter(|x| x.starts_with('#'))
+        .collect::<Vec<_>>();
+    assert_eq!(headings, ["# [1/3] a", "# [2/3] b", "# [3/3] all"]);
+
+    // One total across all targets requested on the command line
+    let output = stdout(&mkrs(&dir, &["a", "b", "done.txt"]));
+    let headings = output
+        .lines()
+        .filter(|x| x.starts_with('#'))
+        .collect::<Vec<_>>();
+    assert_eq!(headings, ["# [1/2] a", "# [2/2] b"]);
+}
+
+#[test]
+fn dependency_path_expansion() {
+    let dir = project("# show\n\n* `~/{dirname}/*.txt`\n\n```\necho {0}\n```\n");
+    write(&dir, "a.txt", "");
+    write(&dir, "b.txt", "");
+    let home = dir.parent().unwrap().display().to_string();
+    let output = mkrs_env(&dir, &["-l", "show"], &[("HOME", &home)]);
+    assert!(output.status.success());
+    let a = dir.join("a.txt").display().to_string();
+    let b = dir.join("b.txt").display().to_string();
+    assert_eq!(
+        stdout(&output),
+        format!("* show\n    * `{a}`\n    * `{b}`\n\n")
+    );
+}
+
+#[test]
+fn keep_going_until() {
+    let mut makefile = String::new();
+    for name in ["a", "b", "c", "d"] {
+        makefile.push_str(&format!(
+            "# {name}\n\n```\necho run-{name}\nexit 1\n```\n\n"
+        ));
+    }
+    let dir = project(&makefile);
+    let output = mkrs(&dir, &["--keep-going-until", "2", "a", "b", "c", "d"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = stdout(&output);
+    assert!(stdout.contains("\nrun-a\n"));
+    assert!(stdout.contains("\nrun-b\n"));
+    assert!(!stdout.contains("run-c"));
+    assert!(!stdout.contains("run-d"));
+}
+
+#[test]
+fn output_annotation() {
+    let dir = project(
+        "# docs {output=site/index.html}\n\n* `src.md`\n\n```\nmkdir -p site\n\
+        echo built > site/index.html\n```\n",
+    );
+    write(&dir, "src.md", "");
+    std::thread::sleep(std::time::Duration::from_millis(10));
+
+    assert!(stdout(&mkrs(&dir, &["docs"])).contains("$ mkdir -p site"));
+    assert_eq!(read(&dir, "site/index.html"), "built\n");
+    assert!(!stdout(&mkrs(&dir, &["docs"])).contains("$ mkdir -p site"));
+
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    write(&dir, "src.md", "changed");
+    assert!(stdout(&mkrs(&dir, &["docs"])).contains("$ mkdir -p site"));
+}
+
+#[test]
+fn effective_config() {
+    let dir = project("# a\n\n```\necho {args}\n```\n\n<!-- include b.md -->\n");
+    write(&dir, "b.md", "# b\n\n* a\n\n```\necho b\n```\n");
+    let output = mkrs(&dir, &["--effective-config", "--", "hello"]);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "# a\n\n```\necho hello\n```\n\n# b\n\n* a\n\n```\necho b\n```\n\n"
+    );
+}
+
+#[test]
+fn redirect_output() {
+    let dir = project(
+        "# a\n\n```{stdout=out.log stderr=err.log}\necho to-stdout\necho to-stderr >&2\n```\n",
+    );
+    let output = mkrs(&dir, &["a"]);
+    assert!(output.status.success());
+    assert!(!stdout(&output).contains("\nto-stdout\n"));
+    assert!(!stderr(&output).contains("to-stderr"));
+    assert_eq!(read(&dir, "out.log"), "to-stdout\n");
+    assert_eq!(read(&dir, "err.log"), "to-stderr\n");
+}
+
+#[test]
+fn config_glob() {
+    let dir = scratch();
+    for name in ["a", "b", "c"] {
+        write(
+            &dir,
+            &format!("tasks/{name}.md"),
+            &format!("# {name}\n\n```\necho run-{name}\n```\n"),
+        );
+    }
+    let output = mkrs(&dir, &["-f", "tasks/*.md", "-l"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "* a\n* b\n* c\n\n");
+
+    let output = mkrs(&dir, &["-f", "tasks/*.md", "c"]);
+    assert!(stdout(&output).contains("\nrun-c\n"));
+
+    let output = mkrs(&dir, &["-f", "none/*.md"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("No configuration files match `none/*.md`"));
+}
+
+#[test]
+fn quiet_unless_fail() {
+    let dir = project(
+        "# ok\n\n```\necho ok-output\n```\n\n# fail\n\n```\necho fail-output\nexit 1\n```\n",
+    );
+    let output = mkrs(&dir, &["--quiet-unless-fail", "ok"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "");
+
+    let output = mkrs(&dir, &["--quiet-unless-fail", "fail"]);
+    assert!(!output.status.success());
+    assert!(stdout(&output).contains("fail-output"));
+}
+
+#[test]
+fn deprecated() {
+    let dir = project(
+        "# old {deprecated=\"use new instead\"}\n\n```\ntrue\n```\n\n# older {deprecated}\n\n\
+        ```\ntrue\n```\n\n# new\n\n```\ntrue\n```\n",
+    );
+    let output = mkrs(&dir, &["old"]);
+    assert!(output.status.success());
+    assert!(stderr(&output).contains("Target `old` is deprecated: use new instead"));
+    assert!(stderr(&mkrs(&dir, &["older"])).contains("Target `older` is deprecated"));
+    assert!(!stderr(&mkrs(&dir, &["new"])).contains("deprecated"));
+}
+
+#[test]
+fn dynamic_dependencies() {
+    let dir = project(
+        "# `main.o` {deps-command=\"cat main.d\"}\n\n* `main.c`\n\n```\necho compiled\n\
+        touch main.o\n```\n",
+    );
+    write(&dir, "main.d", "main.o: main.c main.h\n");
+    write(&dir, "main.c", "");
+    write(&dir, "main.h", "");
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    write(&dir, "main.o", "");
+
+    let args = ["--allow-dynamic-deps", "main.o"];
+    assert!(!stdout(&mkrs(&dir, &args)).contains("compiled"));
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    write(&dir, "main.h", "changed");
+    assert!(!stdout(&mkrs(&dir, &["main.o"])).contains("compiled"));
+    assert!(stdout(&mkrs(&dir, &args)).contains("\ncompiled\n"));
+}
+
+#[test]
+fn up_to_date_count() {
+    let dir = project(
+        "# all\n\n* `a`\n* `b`\n\n```\ntrue\n```\n\n# `a`\n\n```\ntouch a\n```\n\n\
+        # `b`\n\n```\ntouch b\n```\n",
+    );
+    write(&dir, "a", "");
+    write(&dir, "b", "");
+    let output = stdout(&mkrs(&dir, &["-vv"]));
+    assert!(output.contains("*2 target(s) up to date*"));
+    assert!(!output.contains("*Up to date*"));
+
+    let output = stdout(&mkrs(&dir, &["-vv", "--verbose-uptodate"]));
+    assert_eq!(output.matches("*Up to date*").count(), 2);
+    assert!(!output.contains("target(s) up to date"));
+}
+
+#[test]
+fn no_substitution() {
+    let dir = project("# a\n\n```\necho '{target}'\n```\n");
+    assert!(stdout(&mkrs(&dir, &["a"])).contains("\na\n"));
+    assert!(stdout(&mkrs(&dir, &["--no-substitution", "a"])).contains("\n{target}\n"));
+}
+
+#[test]
+fn time_limit() {
+    let dir = project("# slow\n\n```\nsleep 10\necho done\n```\n");
+    let start = std::time::Instant::now();
+    let output = mkrs(&dir, &["--time-limit", "1"]);
+    assert!(start.elapsed() < std::time::Duration::from_secs(8));
+    assert_eq!(output.status.code(), Some(16));
+    assert!(stderr(&output).contains("Time limit of 1s exceeded"));
+    assert!(!stdout(&output).contains("\ndone\n"));
+}
+
+#[test]
+fn graph_mermaid() {
+    let dir = project("# a\n\n* b\n\n# b\n\n* `c.txt`\n");
+    let output = mkrs(&dir, &["--graph", "mermaid"]);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "graph TD\n    n0[\"a\"]\n    n1[\"b\"]\n    n2[\"c.txt\"]\n    n0 --> n1\n    \
+        n1 --> n2\n"
+    );
+}
+
+#[test]
+fn allow_commands() {
+    let dir =
+        project("# ok\n\n```\necho allowed\n```\n\n# bad\n\n```\necho before\nrm -f x\n```\n");
+    let output = mkrs(&dir, &["--allow-commands", "echo,true", "ok"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("\nallowed\n"));
+
+    let output = mkrs(&dir, &["--allow-commands", "echo,true", "bad"]);
+    assert_eq!(output.status.code(), Some(17));
+    assert!(stderr(&output).contains("runs a disallowed command: `rm`"));
+    assert!(!stdout(&output).contains("\nbefore\n"));
+}
+
+#[test]
+fn list_source() {
+    let dir = project("# a\n\n```\ntrue\n```\n\n<!-- include more/b.md -->\n");
+    write(&dir, "more/b.md", "# b\n\n```\ntrue\n```\n");
+    let output = mkrs(&dir, &["-l", "--source"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "* a (Makefile.md)\n* b (more/b.md)\n\n");
+}
+
+#[test]
+fn if_cmd() {
+    let dir = project(
+        "# a\n\n```{if-cmd=\"test -f flag\"}\necho guarded\n```\n\n```\necho always\n```\n",
+    );
+    let output = mkrs(&dir, &["a"]);
+    assert!(output.status.success());
+    assert!(!stdout(&output).contains("\nguarded\n"));
+    assert!(stdout(&output).contains("\nalways\n"));
+
+    write(&dir, "flag", "");
+    let output = mkrs(&dir, &["a"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("\nguarded\n"));
+
+    let output = mkrs(&dir, &["--allow-commands", "echo", "a"]);
+    assert_eq!(output.status.code(), Some(17));
+    assert!(stderr(&output).contains("runs a disallowed command: `test`"));
+}
+
+#[test]
+fn list_glob() {
+    let dir = project(
+        "# test-a\n\n* base\n\n```\ntrue\n```\n\n# test-b\n\n```\ntrue\n```\n\n# base\n\n\
+        ```\ntrue\n```\n",
+    );
+    let output = mkrs(&dir, &["-l", "test-*"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "* test-a\n    * base\n* test-b\n\n");
+    assert_eq!(mkrs(&dir, &["-l", "none-*"]).status.code(), Some(5));
+}
+
+#[test]
+fn no_deps() {
+    let dir = project("# a\n\n* b\n\n```\necho run-a\n```\n\n# b\n\n```\necho run-b\n```\n");
+    let output = mkrs(&dir, &["--no-deps", "a"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("\nrun-a\n"));
+    assert!(!stdout(&output).contains("run-b"));
+}
+
+#[test]
+fn describe() {
+    let dir = project("# `out`\n\nBuild the output\n\n* `in`\n\n```sh\ncp in out\n```\n");
+    write(&dir, "out", "");
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    write(&dir, "in", "");
+    let output = mkrs(&dir, &["--describe", "out"]);
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    assert!(stdout.contains("Build the output"));
+    assert!(stdout.contains("* Kind: file\n"));
+    assert!(stdout.contains("* Dependencies: `in`\n"));
+    assert!(stdout.contains("* Status: outdated: `in` is newer\n"));
+    assert!(stdout.contains("```sh\ncp in out\n```"));
+    assert_eq!(read(&dir, "out"), "");
+}
+
+#[test]
+fn run_inline() {
+    let dir = project("# a\n\n```\necho a1\n{run:b}\necho a2\n```\n\n# b\n\n```\necho b1\n```\n");
+    let output = mkrs(&dir, &["a"]);
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    let a1 = stdout.find("\na1\n").unwrap();
+    let b1 = stdout.find("\nb1\n").unwrap();
+    let a2 = stdout.find("\na2\n").unwrap();
+    assert!(a1 < b1 && b1 < a2);
+    assert!(!stdout.contains("# b"));
+
+    write(
+        &dir,
+        "Makefile.md",
+        "# c\n\n```\n{run:d}\n```\n\n# d\n\n```\n{run:c}\n```\n",
+    );
+    let output = mkrs(&dir, &["c"]);
+    assert_eq!(output.status.code(), Some(18));
+    assert!(stderr(&output).contains("Recursive `{run:c}` in target `d`"));
+}
+
+#[test]
+fn plan_only() {
+    let dir = project(
+        "# all\n\n* `mid`\n* `other`\n\n```\ntrue\n```\n\n\
+        # `mid`\n\n* `leaf`\n\n```\ntouch mid\n```\n\n\
+        # `leaf`\n\n* `src`\n\n```\ntouch leaf\n```\n\n# `other`\n\n```\ntouch other\n```\n",
+    );
+    for file in ["other", "leaf", "mid", "src"] {
+        write(&dir, file, "");
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    let output = mkrs(&dir, &["--plan-only", "all"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "leaf\nmid\nall\n");
+
+    // A phony target without recipes is not listed
+    write(
+        &dir,
+        "Makefile.md",
+        "# group\n\n* a\n\n# a\n\n```\ntrue\n```\n",
+    );
+    let output = mkrs(&dir, &["--plan-only", "group"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "a\n");
+}
+
+#[test]
+fn override_extend() {
+    let dir = project(
+        "# a\n\n```\necho base-a\n```\n\n# b\n\n* a\n\n```\necho base-b\n```\n\n\
+        <!-- include local.md -->\n",
+    );
+    write(
+        &dir,
+        "local.md",
+        "# a {override}\n\n```\necho local-a\n```\n\n# b {extend}\n\n```\necho local-b\n```\n",
+    );
+    let output = mkrs(&dir, &["b"]);
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    assert!(!stdout.contains("base-a"));
+    assert!(stdout.contains("\nlocal-a\n"));
+    assert!(stdout.find("\nbase-b\n").unwrap() < stdout.find("\nlocal-b\n").unwrap());
+
+    write(&dir, "local.md", "# a\n\n```\necho local-a\n```\n");
+    let output = mkrs(&dir, &["a"]);
+    assert_eq!(output.status.code(), Some(19));
+    assert!(
+        stderr(&output).contains("Target `a` in `local.md` is already defined in `Makefile.md`")
+    );
+
+    // The same file loaded via different paths is not a duplicate definition
+    write(
+        &dir,
+        "Makefile.md",
+        "# a\n\n```\necho a\n```\n\n<!-- include common.md -->\n",
+    );
+    write(&dir, "common.md", "# c\n\n```\necho c\n```\n");
+    write(
+        &dir,
+        "sub/x.md",
+        "# x\n\n* c\n\n<!-- include ../common.md -->\n",
+    );
+    for args in [
+        &["-f", "Makefile.md", "-f", "./common.md", "c"][..],
+        &["-f", "Makefile.md", "-f", "sub/x.md", "x"],
+    ] {
+        let output = mkrs(&dir, args);
+        assert!(output.status.success());
+        assert!(self::stdout(&output).contains("\nc\n"));
+    }
+}
+
+#[test]
+fn dry_run_prefix() {
+    let dir =
+        project("# a\n\n* b\n\n```\necho one\necho two\n```\n\n# b\n\n```\necho three\n```\n");
+    let output = mkrs(&dir, &["-n", "--dry-run-prefix", "+ ", "a"]);
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "# [1/2] b\n\n+ echo three\n\n# [2/2] a\n\n+ echo one\n+ echo two\n\n"
+    );
+}
+
+#[test]
+fn task_list() {
+    let dir = project(
+        "# a\n\n- [ ] b\n- [x] c\n\n# b\n\n```\necho run-b\n```\n\n# c\n\n```\necho run-c\n```\n",
+    );
+    let output = mkrs(&dir, &["a"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("\nrun-b\n"));
+    assert!(stdout(&output).contains("\nrun-c\n"));
+}
+
+#[test]
+fn version_json() {
+    let output = mkrs(&scratch(), &["--version", "--json"]);
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    assert!(stdout.starts_with(&format!("{{\"version\":\"{}\",", env!("CARGO_PKG_VERSION"))));
+    for feature in [
+        "\"once\"",
+        "\"tags\"",
+        "\"theme\"",
+        "\"trace-file\"",
+        "\"dry-run\"",
+    ] {
+        assert!(stdout.contains(feature));
+    }
+    assert!(stdout.contains("\"shell\":\"sh -c\",\"script_shell\":\"bash -eo pipefail\""));
+    assert!(stdout.trim_end().ends_with('}'));
+}
+
+#[test]
+fn tags() {
+    let dir = project(
+        "# a {tags=ci,fast}\n\n```\necho run-a\n```\n\n# b {tags=ci}\n\n```\necho run-b\n```\n\n\
+        # c {tags=slow}\n\n```\necho run-c\n```\n",
+    );
+    let output = mkrs(&dir, &["--tag", "ci"]);
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    assert!(stdout.contains("\nrun-a\n"));
+    assert!(stdout.contains("\nrun-b\n"));
+    assert!(!stdout.contains("run-c"));
+
+    let output = mkrs(&dir, &["--tag", "fast", "--tag", "slow"]);
+    assert!(output.status.success());
+    let stdout = self::stdout(&output);
+    assert!(stdout.contains("\nrun-a\n"));
+    assert!(!stdout.contains("run-b"));
+    assert!(stdout.contains("\nrun-c\n"));
+}
+
+#[test]
+fn show_order() {
+    let dir =
+        project("# all\n\n* b\n* c\n\n# b\n\n* d\n\n# c\n\n* d\n* `e.txt`\n\n# d\n\n# `e.txt`\n");
+    let output = mkrs(&dir, &["--show-order", "all"]);
+    assert!(output.status.success());
+    let order = stdout(&output)
+        .lines()
+        .map(|x| x.split(' ').nth(1).unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(order.len(), 5);
+    for (target, dependency) in [
+        ("all", "b"),
+        ("all", "c"),
+        ("b", "d"),
+        ("c", "d"),
+        ("c", "e.txt"),
+    ] {
+        let position = |name| order.iter().position(|x| x == name).unwrap();
+        assert!(position(dependency) < position(target));
+    }
+}
+
+#[test]
+fn login_shell() {
+    let dir = project("# a\n\n```\necho \"login $0\"\nshopt -q login_shell && echo yes\n```\n");
+    let output = mkrs(&dir, &["--login-shell", "a"]);
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    assert!(stdout.contains("```bash -leo pipefail\n"));
+    assert!(stdout.contains("\nyes\n"));
+
+    let output = mkrs(&dir, &["-s", "a"]);
+    assert!(!output.status.success());
+    assert!(!self::stdout(&output).contains("\nyes\n"));
+}
+
+#[test]
+fn wildcard_shell() {
+    let dir = project(
+        "# all\n\n* `a.out`\n* `b.log`\n\n\
+        # `*.out` {shell=sh}\n\n* `*.md`\n\n```\necho \"$0 {0}\"\n```\n\n\
+        # `*.log`\n\n* `*.md`\n\n```sh\necho \"$0 {0}\"\n```\n",
+    );
+    write(&dir, "a.md", "");
+    write(&dir, "b.md", "");
+    let output = mkrs(&dir, &["all"]);
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    assert!(stdout.contains("\nsh a.md\n"));
+    assert!(stdout.contains("\nsh b.md\n"));
+}
+
+#[test]
+fn trace_file() {
+    let dir = project("# a\n\n* b\n\n```\ntrue\n```\n\n# b\n\n```\ntrue\n```\n");
+    let output = mkrs(&dir, &["--trace-file", "trace.json", "a"]);
+    assert!(output.status.success());
+    let trace = read(&dir, "trace.json");
+    assert!(trace.starts_with("{\n  \"traceEvents\": [\n"));
+    for name in ["a", "b"] {
+        for phase in ["B", "E"] {
+            let event = format!("{{\"name\": \"{name}\", \"cat\": \"target\", \"ph\": \"{phase}\"");
+            assert_eq!(trace.matches(&event).count(), 1);
+        }
+    }
+}
+
+#[test]
+fn verify_mtime() {
+    let dir = project("# `out`\n\n* `in`\n\n```\necho forgot to touch out\n```\n");
+    write(&dir, "out", "");
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    write(&dir, "in", "");
+
+    let warning = "File target `out` is older than dependency `in` after running its recipes";
+    let output = mkrs(&dir, &["--verify-mtime", "out"]);
+    assert!(output.status.success());
+    assert!(stderr(&output).contains(warning));
+    assert!(!stderr(&mkrs(&dir, &["out"])).contains(warning));
+    assert_eq!(
+        mkrs(&dir, &["--verify-mtime", "--strict", "out"])
+            .status
+            .code(),
+        Some(13)
+    );
+
+    write(
+        &dir,
+        "Makefile.md",
+        "# `out`\n\n* `in`\n\n```\ntouch out\n```\n",
+    );
+    let output = mkrs(&dir, &["--verify-mtime", "out"]);
+    assert!(!stderr(&output).contains(warning));
+}
+
+#[test]
+fn repeat() {
+    let dir = project(
+        "# flaky\n\n* dep\n\n```\necho run >> runs\ntest $(wc -l < runs) -ne 2\n```\n\n\
+        # dep\n\n```\necho dep >> deps\n```\n\n# once {once}\n\n```\ntrue\n```\n",
+    );
+    let output = mkrs(&dir, &["--repeat", "3", "flaky"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(read(&dir, "runs").lines().count(), 3);
+    assert_eq!(read(&dir, "deps").lines().count(), 1);
+    assert!(stdout(&output).contains("*Target `flaky` ran 3 time(s): 2 passed, 1 failed*"));
+
+    let output = mkrs(&dir, &["--repeat", "2", "--repeat-deps", "flaky"]);
+    assert!(output.status.success());
+    assert_eq!(read(&dir, "deps").lines().count(), 3);
+
+    let output = mkrs(&dir, &["--repeat", "3", "once"]);
+    assert!(output.status.success());
+    assert!(
+        stdout(&output).contains("*Target `once` ran 1 time(s): 1 passed, 0 failed (2 skipped)*")
+    );
+}
+
+#[test]
+fn inputs() {
+    let dir = project(
+        "# `site.html` {inputs=template.html}\n\n* `page.md`\n\n```\necho built\n\
+        touch site.html\n```\n",
+    );
+    write(&dir, "page.md", "");
+    write(&dir, "template.html", "");
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    write(&dir, "site.html", "");
+
+    assert!(!stdout(&mkrs(&dir, &["site.html"])).contains("\nbuilt\n"));
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    write(&dir, "template.html", "changed");
+    assert!(stdout(&mkrs(&dir, &["site.html"])).contains("\nbuilt\n"));
+    assert_eq!(
+        stdout(&mkrs(&dir, &["--print-deps", "site.html"])),
+        "page.md\n"
+    );
+}
+
+#[test]
+fn color_never() {
+    let dir = project("# a\n\n* `in`\n\n```\ntrue\nexit 1\n```\n\n# `in`\n\n```\ntouch in\n```\n");
+    let output = mkrs(&dir, &["--color", "never", "-vv", "a"]);
+    assert!(!output.status.success());
+    assert!(!output.stdout.contains(&0x1b));
+    assert!(!output.stderr.contains(&0x1b));
+    assert!(!mkrs(&dir, &["--color", "never", "-l", "a"])
+        .stdout
+        .contains(&0x1b));
+    assert!(!mkrs(&dir, &["--color", "never", "--describe", "a"])
+        .stdout
+        .contains(&0x1b));
+}
+
+#[test]
+fn path_augmentation() {
+    let dir = project("# a {path+=./bin}\n\n```\nhello-mkrs\n```\n\n# b\n\n```\nhello-mkrs\n```\n");
+    script(&dir, "bin/hello-mkrs", "#!/bin/sh\necho hello from bin\n");
+
+    let output = mkrs(&dir, &["a"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("\nhello from bin\n"));
+    assert!(!mkrs(&dir, &["b"]).status.success());
+}
+
+#[test]
+fn list_produced() {
+    let dir = project(
+        "# all\n\n* `a.txt`\n* `b.txt`\n\n# `a.txt`\n\n```\nprintf 12345 > a.txt\n```\n\n\
+        # `b.txt`\n\n```\ntouch b.txt\n```\n",
+    );
+    write(&dir, "b.txt", "");
+    let output = mkrs(&dir, &["--list-produced", "all"]);
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    assert!(stdout.contains("# Produced files\n\nFile | Size | Modified\n"));
+    assert!(stdout.contains("\na.txt | 5 B | "));
+    assert!(!stdout.contains("\nb.txt |"));
+}
+
+#[test]
+fn shell_mapping() {
+    let dir =
+        project("# .SHELLS {py=\"sh -s\" js=node}\n\n# a\n\n```py\necho \"mapped $0\"\n```\n");
+    let output = mkrs(&dir, &[]);
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    assert!(stdout.contains("```sh -s\n"));
+    assert!(stdout.contains("\nmapped sh\n"));
+    assert!(!stdout.contains(".SHELLS"));
+}
+
+#[test]
+fn dry_run_producibility() {
+    let dir = project(
+        "# all\n\n* `generated.h`\n* `present.h`\n* `built.o`\n\n```\ntrue\n```\n\n\
+        # `built.o`\n\n```\ntouch built.o\n```\n",
+    );
+    write(&dir, "present.h", "");
+    let output = mkrs(&dir, &["-n", "all"]);
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = stderr(&output);
+    assert!(stderr.contains("File `generated.h` does not exist"));
+    assert!(!stderr.contains("present.h"));
+    assert!(!stderr.contains("built.o"));
+    assert!(stderr.contains("Dry run found 1 problem(s)"));
+}
+
+#[test]
+fn styled_dumps() {
+    let dir =
+        project("# a\n\n* b\n\n```\n# comment\nif true; then echo \"$HOME\"; fi\n```\n\n# b\n");
+    for args in [["--describe", "a"], ["--format-config", "-B"]] {
+        let output = mkrs(&dir, &[&args[..], &["--color", "always"]].concat());
+        assert!(output.status.success());
+        assert!(output.stdout.contains(&0x1b));
+
+        // Keywords and strings in the recipe are highlighted
+        assert!(stdout(&output).contains("m\"$HOME\"\x1b[0m"));
+        assert!(stdout(&output).contains("mthen\x1b[0m"));
+        let output = mkrs(&dir, &[&args[..], &["--color", "never"]].concat());
+        assert!(!output.stdout.contains(&0x1b));
+    }
+}
+
+#[test]
+fn mkrsignore() {
+    let dir = project("# all\n\n* `**/*.rs`\n\n```\ntrue\n```\n");
+    write(&dir, "src/main.rs", "");
+    write(&dir, "target/gen.rs", "");
+    write(&dir, ".mkrsignore", "target/\n");
+    let output = mkrs(&dir, &["-l", "all"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "* all\n    * `src/main.rs`\n\n");
+
+    write(&dir, ".mkrsignore", "[\n");
+    let output = mkrs(&dir, &["--strict", "all"]);
+    assert_eq!(output.status.code(), Some(13));
+    assert!(stderr(&output).contains("Invalid pattern `[`"));
+}
+
+#[test]
+fn one_shell() {
+    let dir = project("# a\n\n```\nX=carried\n```\n\n```\necho \"x=$X\"\n```\n");
+    assert!(stdout(&mkrs(&dir, &["-s", "a"])).contains("\nx=\n"));
+    let output = mkrs(&dir, &["-s", "--one-shell", "a"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("\nx=carried\n"));
+}
+
+#[test]
+fn once() {
+    let dir = project(
+        "# a\n\n* setup\n\n```\ntrue\n```\n\n# b\n\n* setup\n\n```\ntrue\n```\n\n\
+        # setup {once}\n\n```\necho run-setup\n```\n",
+    );
+    let output = mkrs(&dir, &["a", "b", "setup"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output).matches("\nrun-setup\n").count(), 1);
+}
+
+#[test]
+fn fingerprint() {
+    let dir = project("# a\n\n* `in.txt`\n\n```\necho a\n```\n");
+    write(&dir, "in.txt", "one");
+    let fingerprint = || {
+        let output = mkrs(&dir, &["--fingerprint", "a"]);
+        assert!(output.status.success());
+        stdout(&output)
+    };
+    let original = fingerprint();
+    assert_eq!(original.trim().len(), 16);
+    assert_eq!(fingerprint(), original);
+
+    write(&dir, "in.txt", "two");
+    let changed = fingerprint();
+    assert_ne!(changed, original);
+
+    write(
+        &dir,
+        "Makefile.md",
+        "# a\n\n* `in.txt`\n\n```\necho b\n```\n",
+    );
+    assert_ne!(fingerprint(), changed);
+}
+
+#[test]
+fn no_headings() {
+    let dir = project("# a\n\n* b\n\n```\necho run-a\n```\n\n# b\n\n```\necho run-b\n```\n");
+    let output = mkrs(&dir, &["--no-headings", "a"]);
+    assert!(output.status.success());
+    let stdout = stdout(&output);
+    assert!(!stdout.contains("# "));
+    assert!(stdout.contains("\nrun-a\n"));
+    assert!(stdout.contains("\nrun-b\n"));
+}
+
+#[test]
+fn deps_glob() {
+    let dir = project(
+        "# test {deps-glob=test-*}\n\n# test-a\n\n```\necho run-a\n```\n\n# other\n\n\
+        ```\necho run-other\n```\n",
+    );
+    let output = mkrs(&dir, &["test"]);
+    assert!(stdout(&output).contains("\nrun-a\n"));
+    assert!(!stdout(&output).contains("run-other"));
+
+    let makefile = read(&dir, "Makefile.md");
+    write(
+        &dir,
+        "Makefile.md",
+        &format!("{makefile}\n# test-b\n\n```\necho run-b\n```\n"),
+    );
+    let output = mkrs(&dir, &["test"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("\nrun-a\n"));
+    assert!(stdout(&output).contains("\nrun-b\n"));
+}
+
+#[test]
+fn log_dir() {
+    let dir = project(
+        "# Build All\n\n```\necho out\necho err >&2\n```\n\n```sh\necho script\n```\n\n\
+        # build-all\n\n```\necho other\n```\n",
+    );
+    let plain = mkrs(&dir, &["Build All"]);
+    let logged = mkrs(&dir, &["--log-dir", "logs", "Build All", "build-all"]);
+    assert!(logged.status.success());
+    assert!(
+        stdout(&logged).starts_with(&stdout(&plain).replace("# Build All", "# [1/2] Build All"))
+    );
+
+    // Targets with the same slug have separate log files
+    assert_eq!(read(&dir, "logs/build-all.log"), "$ echo other\nother\n");
+    let logs = std::fs::read_dir(dir.join("logs"))
+        .unwrap()
+        .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+        .filter(|x| x != "build-all.log")
+        .collect::<Vec<_>>();
+    assert_eq!(logs.len(), 1);
+    assert!(logs[0].starts_with("build-all-"));
+    assert_eq!(
+        read(&dir, &format!("logs/{}", logs[0])),
+        "$ echo out\nout\n$ echo err >&2\nerr\n$ echo script\nscript\n"
+    );
+}
+
+#[test]
+fn step_without_terminal() {
+    let dir = project("# a\n\n```\necho ran\n```\n");
+    let output = mkrs(&dir, &["--step", "a"]);
+    assert_eq!(output.status.code(), Some(20));
+    assert!(stderr(&output).contains("`--step` requires a terminal"));
+    assert!(!stdout(&output).contains("\nran\n"));
+}
+
+#[test]
+fn explain() {
+    let dir = project("# all\n\n* `a.o`\n\n# `*.o`\n\n* `*.c`\n\n```\necho cc {0}\n```\n");
+    write(&dir, "a.c", "");
+    let output = mkrs(&dir, &["--explain", "a.o"]);
+    assert!(output.status.success());
+    assert!(stderr(&output).contains("Wildcard target `*.o` matches `a.o` with dependency `a.c`"));
+    assert!(stdout(&output).contains("\ncc a.c\n"));
+
+    let output = mkrs(&dir, &["--explain", "x.txt"]);
+    assert!(stderr(&output).contains("No wildcard target matches `x.txt`"));
+}
+
+#[test]
+fn alternatives() {
+    let dir = project(
+        "# install\n\n```{alt=cargo}\necho via-cargo\n```\n\n```{alt=brew}\necho via-brew\n```\n\n\
+        ```\necho always\n```\n",
+    );
+    let output = stdout(&mkrs(&dir, &["install"]));
+    assert!(output.contains("\nvia-cargo\n"));
+    assert!(!output.contains("via-brew"));
+    assert!(output.contains("\nalways\n"));
+
+    let output = stdout(&mkrs(&dir, &["--alt", "brew", "install"]));
+    assert!(output.contains("\nvia-brew\n"));
+    assert!(!output.contains("via-cargo"));
+    assert!(output.contains("\nalways\n"));
+}
+
+#[test]
+fn lost_state_warning() {
+    let dir = project("# a\n\n```\ncd sub\npwd\n```\n\n# b\n\n```\ncd sub && pwd\n```\n");
+    let warning = "Recipe for target `a` runs `cd sub` in its own shell";
+    let output = mkrs(&dir, &["--check"]);
+    assert!(output.status.success());
+    assert!(stderr(&output).contains(warning));
+    assert!(!stderr(&output).contains("target `b`"));
+    assert!(!stderr(&mkrs(&dir, &["--check", "-s"])).contains(warning));
+}
+
+#[test]
+fn light_theme() {
+    let dir = project("# a\n\n```\ntrue\n```\n");
+    let heading = |args: &[&str], env: &[(&str, &str)]| {
+        let output = mkrs_env(&dir, &[&["--color", "always"], args].concat(), env);
+        stdout(&output).lines().next().unwrap().to_string()
+    };
+    let dark = heading(&["--theme", "dark"], &[]);
+    let light = heading(&["--theme", "light"], &[]);
+    assert!(dark.starts_with('\x1b'));
+    assert!(light.starts_with('\x1b'));
+    assert_ne!(dark, light);
+    assert_eq!(heading(&[], &[("COLORFGBG", "0;15")]), light);
+    assert_eq!(heading(&[], &[]), dark);
+}
+
+#[test]
+fn named_params() {
+    let dir = project(
+        "# deploy {params=env,region=eu tags=ci}\n\n```\necho \"deploy {env} {region}\"\n```\n",
+    );
+    let output = mkrs(&dir, &["deploy", "env=staging"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("\ndeploy staging eu\n"));
+
+    let output = mkrs(&dir, &["deploy", "env=prod", "region=us"]);
+    assert!(stdout(&output).contains("\ndeploy prod us\n"));
+
+    let output = mkrs(&dir, &["--tag", "ci", "env=staging"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("\ndeploy staging eu\n"));
+
+    let output = mkrs(&dir, &["deploy"]);
+    assert_eq!(output.status.code(), Some(21));
+    assert!(stderr(&output).contains("Target `deploy` requires parameter `env`"));
+}